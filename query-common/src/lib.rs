@@ -1,21 +1,120 @@
+/// Functions and types of the host.
+/// Its types are distinct from the ones a plugin exports, even if they have the same names.
+pub mod host {
+    wai_bindgen_rust::import!("../query-runner/query-host.wai");
 
+    pub use query_host::*;
+}
+
+pub use host::LogLevel;
+
+/// Log a message through the host, which tags it with the plugin name.
+pub fn log(level: LogLevel, message: &str) {
+    host::log(level, message)
+}
+
+/// Current time, as microseconds since the Unix epoch, in UTC.
+pub fn now() -> i64 {
+    host::now()
+}
+
+/// Value of a setting of the plugin, if configured on the host.
+pub fn config_get(key: &str) -> Option<String> {
+    host::config_get(key)
+}
+
+/// Run a query on the connection of the current run, from `row` or `end`.
+/// The host limits how many queries a run can make.
+pub fn query(
+    query_string: &str,
+    variables: &[host::Variable<'_>],
+) -> Result<host::QueryResult, host::PluginError> {
+    host::query(query_string, variables)
+}
+
+/// Build the `QueryMetadata` of a read query with the given description and parameters.
+/// Parameters are required by default, their settings can follow their type:
+/// `"limit" => Integer { required: false, default: "10", description: "Maximum number of rows" }`.
+/// They can also have constraints: `min`, `max`, `max_length`, `pattern` and `allowed` values,
+/// like `"status" => String { allowed: ["open", "closed"] }`.
+/// A `values_query` lists the values users can pick from, with an optional label as second column.
+/// Write queries can override its mode: `QueryMetadata { mode: QueryMode::Write, ..metadata!(...) }`.
 #[macro_export]
 macro_rules! metadata {
-    ( $description:expr, $( $x:expr => $t:ident),* ) => {
+    ( $description:expr, $( $x:expr => $t:ident $({ $($field:ident : $value:expr),* $(,)? })? ),* ) => {
         {  
             paste! {
                 let mut parameters = Vec::new();
                 $(
-                    parameters.push(Parameter {
+                    #[allow(unused_mut)]
+                    let mut parameter = Parameter {
                         name: $x.into(),
                         parameter_type: ParameterType::[<Type $t>],
-                       });
+                        required: true,
+                        default_value: None,
+                        description: String::new(),
+                        constraints: ParameterConstraints {
+                            min: None,
+                            max: None,
+                            max_length: None,
+                            pattern: None,
+                            allowed_values: Vec::new(),
+                        },
+                        values_query: None,
+                       };
+                    $($( $crate::parameter_setting!(parameter, $field, $value); )*)?
+                    parameters.push(parameter);
                 )*
             }
             QueryMetadata {
                 description: $description.into(),
                 parameters,
+                mode: QueryMode::Read,
             }
         }
     };
 }
+
+/// Apply a setting given to a parameter in `metadata!`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! parameter_setting {
+    ( $parameter:ident, required, $value:expr ) => {
+        $parameter.required = $value;
+    };
+    ( $parameter:ident, default, $value:expr ) => {
+        $parameter.default_value = Some($value.into());
+    };
+    ( $parameter:ident, description, $value:expr ) => {
+        $parameter.description = $value.into();
+    };
+    ( $parameter:ident, min, $value:expr ) => {
+        $parameter.constraints.min = Some($value.to_string());
+    };
+    ( $parameter:ident, max, $value:expr ) => {
+        $parameter.constraints.max = Some($value.to_string());
+    };
+    ( $parameter:ident, max_length, $value:expr ) => {
+        $parameter.constraints.max_length = Some($value);
+    };
+    ( $parameter:ident, pattern, $value:expr ) => {
+        $parameter.constraints.pattern = Some($value.into());
+    };
+    ( $parameter:ident, allowed, $value:expr ) => {
+        $parameter.constraints.allowed_values = $value.iter().map(|v| v.to_string()).collect();
+    };
+    ( $parameter:ident, values_query, $value:expr ) => {
+        $parameter.values_query = Some($value.into());
+    };
+}
+
+/// Build a `PluginError` with the given code and formatted message, to abort the query.
+#[macro_export]
+macro_rules! plugin_error {
+    ( $code:expr, $($arg:tt)+ ) => {
+        PluginError {
+            code: $code.into(),
+            message: format!($($arg)+),
+        }
+    };
+}
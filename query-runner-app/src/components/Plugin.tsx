@@ -1,6 +1,12 @@
 import { useState, useEffect, ChangeEvent } from "react";
 import { useParams, Link } from "react-router-dom";
-import { IPluginMetadata, IPluginRun, IQueryResult } from "../types/Plugin";
+import {
+  IParameter,
+  IParameterValue,
+  IPluginMetadata,
+  IPluginRun,
+  IQueryResult,
+} from "../types/Plugin";
 import PluginService from "../services/PluginService";
 import IConnection from "../types/Connection";
 import axios, { AxiosError } from "axios";
@@ -18,6 +24,9 @@ function Plugin(props: PluginProps) {
   const [connection, setConnection] = useState<string>();
   const [results, setResults] = useState<IQueryResult>();
   const [error, setError] = useState<string>();
+  const [parameterValues, setParameterValues] = useState<{
+    [k: string]: IParameterValue[];
+  }>({});
 
   useEffect(() => {
     if (name) {
@@ -25,6 +34,40 @@ function Plugin(props: PluginProps) {
     }
   }, [name]);
 
+  // Options of the parameters listing their values depend on the connection.
+  const selectedConnection = connection || props.connections[0]?.name;
+  useEffect(() => {
+    setParameterValues({});
+    if (!name || !metadata || !selectedConnection) {
+      return;
+    }
+    metadata.parameters
+      .filter((param) => param.values_query !== null)
+      .forEach((param) => {
+        PluginService.getParameterValues(name, selectedConnection, param.name)
+          .then((response: any) => {
+            setParameterValues((values) => ({
+              ...values,
+              [param.name]: response.data,
+            }));
+          })
+          .catch((e: Error | AxiosError) => {
+            console.log(e);
+          });
+      });
+  }, [name, metadata, selectedConnection]);
+
+  // Values from the values query, or else the allowed ones.
+  const options = (param: IParameter): IParameterValue[] => {
+    if (param.values_query !== null) {
+      return parameterValues[param.name] ?? [];
+    }
+    return param.constraints.allowed_values.map((value) => ({
+      value,
+      label: value,
+    }));
+  };
+
   const retrieveMetadata = (name: string) => {
     PluginService.getMetadata(name)
       .then((response: any) => {
@@ -40,19 +83,32 @@ function Plugin(props: PluginProps) {
       });
   };
 
-  const handleInputChange = (event: ChangeEvent<HTMLInputElement>) => {
+  const handleInputChange = (
+    event: ChangeEvent<HTMLInputElement | HTMLSelectElement>
+  ) => {
     const { name, value } = event.target;
     setVariables({ ...variables, [name]: value });
   };
   const handleConnectionChange = (event: ChangeEvent<HTMLSelectElement>) => {
     setConnection(event.target.value);
   };
+  const constraintsHint = (param: IParameter) => {
+    const { min, max, max_length } = param.constraints;
+    const hints = [];
+    if (min !== null) hints.push(`at least ${min}`);
+    if (max !== null) hints.push(`at most ${max}`);
+    if (max_length !== null) hints.push(`up to ${max_length} characters`);
+    return hints.join(", ");
+  };
   const runPlugin = () => {
     setError("");
+    // Leave emptied fields out, so that they get their default value.
     let run: IPluginRun = {
       plugin: name || "",
-      connection: connection || props.connections[0]?.name,
-      variables,
+      connection: selectedConnection,
+      variables: Object.fromEntries(
+        Object.entries(variables).filter(([, value]) => value !== "")
+      ),
     };
     console.log(run);
     PluginService.run(run)
@@ -103,15 +159,44 @@ function Plugin(props: PluginProps) {
                 metadata.parameters &&
                 metadata.parameters.map((param) => (
                   <div className="mb-3" key={param.name}>
-                    <label htmlFor={param.name}>{param.name}:</label>
-                    <input
-                      type="text"
-                      className="form-control"
-                      id={param.name}
-                      required
-                      name={param.name}
-                      onChange={handleInputChange}
-                    />
+                    <label htmlFor={param.name}>
+                      {param.name}
+                      {param.required && param.default === null && " *"}:
+                    </label>
+                    {options(param).length > 0 ? (
+                      <select
+                        className="form-select"
+                        id={param.name}
+                        required={param.required && param.default === null}
+                        name={param.name}
+                        onChange={handleInputChange}
+                      >
+                        <option value="">{param.default ?? ""}</option>
+                        {options(param).map((option) => (
+                          <option key={option.value} value={option.value}>
+                            {option.label}
+                          </option>
+                        ))}
+                      </select>
+                    ) : (
+                      <input
+                        type="text"
+                        className="form-control"
+                        id={param.name}
+                        required={param.required && param.default === null}
+                        placeholder={param.default ?? ""}
+                        maxLength={param.constraints.max_length ?? undefined}
+                        pattern={param.constraints.pattern ?? undefined}
+                        name={param.name}
+                        onChange={handleInputChange}
+                      />
+                    )}
+                    {param.description && (
+                      <div className="form-text">{param.description}</div>
+                    )}
+                    {constraintsHint(param) && (
+                      <div className="form-text">{constraintsHint(param)}</div>
+                    )}
                   </div>
                 ))}
             </div>
@@ -1,4 +1,10 @@
-import { IQueryResult } from "../types/Plugin";
+import { IColumn, IQueryResult } from "../types/Plugin";
+
+// Numbers are aligned to the right.
+const NUMERIC_TYPES = ["integer", "decimal", "numeric"];
+
+const cellClass = (column?: IColumn) =>
+  column && NUMERIC_TYPES.includes(column.type) ? "text-end" : undefined;
 
 interface ResultsProps {
   results: IQueryResult;
@@ -9,8 +15,14 @@ function QueryResults(props: ResultsProps) {
     <table>
       <thead>
         <tr>
-          {props.results.names.map((n, ix) => (
-            <th key={"col" + ix}>{n}</th>
+          {props.results.columns.map((c, ix) => (
+            <th
+              key={"col" + ix}
+              className={cellClass(c)}
+              title={c.db_type ? `${c.type} (${c.db_type})` : c.type}
+            >
+              {c.name}
+            </th>
           ))}
         </tr>
       </thead>
@@ -18,7 +30,12 @@ function QueryResults(props: ResultsProps) {
         {props.results.values.map((row, ix1) => (
           <tr key={"row" + ix1}>
             {row.map((v, ix2) => (
-              <td key={"val" + ix1 + "-" + ix2}>{v}</td>
+              <td
+                key={"val" + ix1 + "-" + ix2}
+                className={cellClass(props.results.columns[ix2])}
+              >
+                {v}
+              </td>
             ))}
           </tr>
         ))}
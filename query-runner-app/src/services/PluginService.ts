@@ -1,5 +1,5 @@
 import http from "../http-common";
-import IPlugin, { IPluginMetadata, IPluginRun, IQueryResult } from "../types/Plugin";
+import IPlugin, { IParameterValue, IPluginMetadata, IPluginRun, IQueryResult } from "../types/Plugin";
 
 const getAll = () => {
   return http.get<Array<IPlugin>>("/plugins");
@@ -13,10 +13,15 @@ const run = (run: IPluginRun) => {
   return http.post<IQueryResult>("/plugins/" + encodeURIComponent(run.plugin)+"/"+encodeURIComponent(run.connection), run.variables);
 }
 
+const getParameterValues = (plugin: string, connection: string, param: string) => {
+  return http.get<Array<IParameterValue>>("/plugins/" + encodeURIComponent(plugin)+"/"+encodeURIComponent(connection)+"/parameters/"+encodeURIComponent(param)+"/values");
+}
+
 const PluginService = {
     getAll,
     getMetadata,
-    run
+    run,
+    getParameterValues
   };
   
 export default PluginService;
@@ -3,19 +3,46 @@ export default interface IPlugin {
   description: string;
 }
 
+export interface IParameterConstraints {
+  min: string | null;
+  max: string | null;
+  max_length: number | null;
+  pattern: string | null;
+  allowed_values: string[];
+}
+
 export interface IParameter {
   name: string;
   parameter_type: string;
+  required: boolean;
+  default: string | null;
+  description: string;
+  constraints: IParameterConstraints;
+  values_query: string | null;
+}
+
+export interface IParameterValue {
+  value: string;
+  label: string;
 }
 
 export interface IPluginMetadata {
   name: string;
   description: string;
   parameters: IParameter[];
+  mode: string;
+}
+
+export interface IColumn {
+  name: string;
+  type: string;
+  nullable: boolean | null;
+  db_type: string | null;
 }
 
 export interface IQueryResult {
   names: string[];
+  columns: IColumn[];
   values: any[][];
 }
 
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
@@ -9,6 +9,9 @@ use query_runner::*;
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// Compiler of the plugins, among the ones built in
+    #[arg(long, global = true, default_value_t)]
+    compiler: Compiler,
     #[command(subcommand)]
     command: Command,
 }
@@ -33,6 +36,21 @@ enum Command {
         /// Name of the connection to use
         #[arg(short, long)]
         connection: String,
+        /// Maximum number of WASM instructions the plugin can execute
+        #[arg(long)]
+        fuel: Option<u64>,
+        /// Maximum duration of the run, in seconds
+        #[arg(long)]
+        timeout: Option<u64>,
+        /// Maximum memory of the plugin, in 64KiB pages
+        #[arg(long)]
+        max_memory: Option<u32>,
+        /// Maximum number of queries the plugin can make itself
+        #[arg(long)]
+        max_sub_queries: Option<u32>,
+        /// Number of rows sent at once to plugins taking them in batches
+        #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+        batch_size: usize,
         /// Parameters in name=value format
         params: Vec<String>,
     },
@@ -48,11 +66,29 @@ enum ConnectionSubCommand {
 enum PluginSubCommand {
     /// List plugins
     List,
+    /// Compile plugins ahead of time, into the cache used when loading them
+    Compile,
+    /// Describe a plugin and its parameters
+    Describe {
+        /// Name of the plugin
+        name: String,
+    },
+    /// List the values a parameter of a plugin can take on a connection
+    Values {
+        /// Name of the plugin
+        name: String,
+        /// Name of the parameter
+        parameter: String,
+        /// Name of the connection to use
+        #[arg(short, long)]
+        connection: String,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
+    let compiler = args.compiler;
     match args.command {
         Command::Connections { subcommand } => match subcommand {
             ConnectionSubCommand::List => {
@@ -64,19 +100,71 @@ async fn main() -> Result<()> {
         },
         Command::Plugins { subcommand } => match subcommand {
             PluginSubCommand::List => {
-                let engine = build_engine();
-                let plugins = load_plugins(&engine, "plugins")?;
+                let plugins = load_plugins(&build_engine(), PLUGINS_FOLDER)?;
                 for name in plugins.keys() {
                     println!("- {name}");
                 }
             }
+            PluginSubCommand::Compile => {
+                // Loading the state caches the plugins compiled with its compiler.
+                let st = State::load_from_disk_with(compiler)?;
+                for name in st.plugins.keys() {
+                    println!("- {name}");
+                }
+                println!("compiled plugins cached in {PLUGIN_CACHE}");
+            }
+            PluginSubCommand::Describe { name } => {
+                let st = State::load_from_disk_with(compiler)?;
+                let metadata = st.get_metadata(st.get_plugin(&name)?)?;
+                println!("{name}: {}", metadata.description);
+                println!("mode: {}", metadata.mode);
+                println!("parameters:");
+                for p in metadata.parameters.iter() {
+                    let mut line = format!("- {} ({})", p.name, p.parameter_type);
+                    match &p.default_value {
+                        Some(default) => line.push_str(&format!(", default {default}")),
+                        None if !p.required => line.push_str(", optional"),
+                        None => {}
+                    }
+                    if !p.description.is_empty() {
+                        line.push_str(&format!(": {}", p.description));
+                    }
+                    println!("{line}");
+                }
+            }
+            PluginSubCommand::Values {
+                name,
+                parameter,
+                connection,
+            } => {
+                let st = State::load_from_disk_with(compiler)?;
+                for v in st.parameter_values(&name, &connection, &parameter).await? {
+                    if v.label == v.value {
+                        println!("- {}", v.value);
+                    } else {
+                        println!("- {}: {}", v.value, v.label);
+                    }
+                }
+            }
         },
         Command::Run {
             plugin,
             connection,
+            fuel,
+            timeout,
+            max_memory,
+            max_sub_queries,
+            batch_size,
             params,
         } => {
-            let st = State::load_from_disk()?;
+            let mut st = State::load_from_disk_with(compiler)?;
+            st.limits = ExecutionLimits {
+                fuel,
+                timeout: timeout.map(Duration::from_secs),
+                max_memory_pages: max_memory,
+                max_sub_queries,
+            };
+            st.batch_size = batch_size;
             let mut variables = HashMap::new();
             for p in params.iter() {
                 if let Some((name, value)) = p.split_once('=') {
@@ -85,7 +173,13 @@ async fn main() -> Result<()> {
                     return Err(anyhow!("{p} is not a valid name=value parameter"));
                 }
             }
-            let res = st.run_untyped(&plugin, &connection, &variables).await?;
+            let res = match st.run_untyped(&plugin, &connection, &variables).await {
+                Ok(res) => res,
+                Err(QueryRunnerError::Killed(killed)) => {
+                    return Err(anyhow!("plugin `{plugin}` was killed: {killed}"))
+                }
+                Err(err) => return Err(err.into()),
+            };
             match res {
                 None => println!("<no result>"),
                 Some(res) => {
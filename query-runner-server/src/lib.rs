@@ -1,6 +1,7 @@
 //! Web service API implementation.
 
 use anyhow::Result;
+use arc_swap::ArcSwap;
 use axum::{
     extract::{Path, State},
     http::StatusCode,
@@ -9,41 +10,150 @@ use axum::{
     Json, Router,
 };
 use http::Method;
-use query_runner::{parse_parameter_values, Parameter};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use query_runner::{
+    Changes, ExecutionLimits, Parameter, ParameterValue, PluginKilled, QueryRunnerError,
+    CONNECTIONS_FILE, PLUGINS_FOLDER,
+};
 use serde::Serialize;
 use serde_json::{json, Value};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tower_http::cors::{Any, CorsLayer};
 
+/// Instruction budget of a single plugin run.
+const RUN_FUEL: u64 = 100_000_000_000;
+/// Maximum duration of a single plugin run.
+const RUN_TIMEOUT: Duration = Duration::from_secs(300);
+/// Maximum memory of a plugin instance, in 64KiB pages (1GiB).
+const RUN_MAX_MEMORY_PAGES: u32 = 16_384;
+/// Maximum number of queries a plugin can make itself during a single run.
+const RUN_MAX_SUB_QUERIES: u32 = 1_000;
+/// Time to wait for file changes to settle before reloading, a copied plugin can take several writes.
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+
 /// State.
 struct AppState {
-    runner: query_runner::State,
+    /// Swapped as a whole by reloads, requests keep the one they started with until they end.
+    runner: ArcSwap<query_runner::State>,
+    /// Held while reloading, so that concurrent reloads don't lose each other's changes.
+    reloading: Mutex<()>,
+}
+
+impl AppState {
+    fn load() -> Result<AppState> {
+        let mut runner = query_runner::State::load_from_disk()?;
+        runner.limits = ExecutionLimits {
+            fuel: Some(RUN_FUEL),
+            timeout: Some(RUN_TIMEOUT),
+            max_memory_pages: Some(RUN_MAX_MEMORY_PAGES),
+            max_sub_queries: Some(RUN_MAX_SUB_QUERIES),
+        };
+        Ok(AppState {
+            runner: ArcSwap::from_pointee(runner),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    /// Reload plugins, connections and plugin settings from disk, and swap them in if they all loaded.
+    /// Blocks while compiling changed plugins.
+    fn reload(&self) -> Result<Reloaded, QueryRunnerError> {
+        let _reloading = self.reloading.lock().unwrap_or_else(|err| err.into_inner());
+        let reload = self.runner.load_full().reload()?;
+        self.runner.store(Arc::new(reload.state));
+        tracing::info!(
+            "reloaded plugins ({}), connections ({}) and plugin settings ({})",
+            reload.plugins,
+            reload.connections,
+            reload.settings
+        );
+        Ok(Reloaded {
+            plugins: reload.plugins,
+            connections: reload.connections,
+            settings: reload.settings,
+        })
+    }
 }
 
 /// App routes.
 pub fn app() -> Result<Router> {
-    let runner_state = Arc::new(AppState {
-        runner: query_runner::State::load_from_disk()?,
-    });
+    Ok(routes(Arc::new(AppState::load()?)))
+}
+
+/// App routes, reloading plugins and connections whenever their files change.
+pub fn watched_app() -> Result<Router> {
+    let runner_state = Arc::new(AppState::load()?);
+    watch(runner_state.clone())?;
+    Ok(routes(runner_state))
+}
 
+/// Routes over the given state.
+fn routes(runner_state: Arc<AppState>) -> Router {
     let cors = CorsLayer::new().allow_origin(Any).allow_methods([Method::GET, Method::POST])
         .allow_headers(Any);
 
-    let app = Router::new()
+    Router::new()
         .route("/connections", get(connections))
         .route("/plugins", get(plugins))
         .route("/plugins/:name", get(plugin_metadata))
         .route("/plugins/:name/:connection", post(plugin_execute))
+        .route(
+            "/plugins/:name/:connection/parameters/:param/values",
+            get(parameter_values),
+        )
+        .route("/admin/reload", post(reload))
         .with_state(runner_state)
-        .layer(cors);
+        .layer(cors)
+}
+
+/// Watch the plugins folder and the configuration folder, reloading on changes.
+/// Must be called from a Tokio runtime, that runs the reloads.
+fn watch(state: Arc<AppState>) -> Result<()> {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        match event {
+            Ok(event) if !event.kind.is_access() => {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("cannot watch files: {err}"),
+        }
+    })?;
+    watcher.watch(std::path::Path::new(PLUGINS_FOLDER), RecursiveMode::NonRecursive)?;
+    // Editors often replace files rather than write them, so watch the folder holding the connections file
+    // (and the plugin settings).
+    let config = std::path::Path::new(CONNECTIONS_FILE)
+        .parent()
+        .unwrap_or(std::path::Path::new("."));
+    watcher.watch(config, RecursiveMode::NonRecursive)?;
 
-    Ok(app)
+    tokio::spawn(async move {
+        // Dropping the watcher stops it.
+        let _watcher: RecommendedWatcher = watcher;
+        while rx.recv().await.is_some() {
+            tokio::time::sleep(RELOAD_DELAY).await;
+            while rx.try_recv().is_ok() {}
+            let state = state.clone();
+            match tokio::task::spawn_blocking(move || state.reload()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => {
+                    tracing::error!("cannot reload, keeping the current plugins and connections: {err}")
+                }
+                Err(err) => tracing::error!("reload failed: {err}"),
+            }
+        }
+    });
+    Ok(())
 }
 
 /// List connections.
 async fn connections(State(state): State<Arc<AppState>>) -> Json<Vec<Connection>> {
+    let runner = state.runner.load();
     let mut conns = Vec::new();
-    for (name, conn) in state.runner.connections.iter() {
+    for (name, conn) in runner.connections.iter() {
         conns.push(Connection {
             name: name.to_owned(),
             db_type: conn.db_type(),
@@ -55,9 +165,10 @@ async fn connections(State(state): State<Arc<AppState>>) -> Json<Vec<Connection>
 
 /// List plugins.
 async fn plugins(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Plugin>>, AppError> {
+    let runner = state.runner.load();
     let mut conns = Vec::new();
-    for (name, module) in state.runner.plugins.iter() {
-        match state.runner.get_metadata(module) {
+    for (name, module) in runner.plugins.iter() {
+        match runner.get_metadata(module) {
             Ok(metadata) => conns.push(Plugin {
                 name: name.to_owned(),
                 description: metadata.description,
@@ -77,12 +188,14 @@ async fn plugin_metadata(
     State(state): State<Arc<AppState>>,
     Path(name): Path<String>,
 ) -> Result<Json<PluginMetadata>, AppError> {
-    match state.runner.plugins.get(&name) {
-        Some(module) => match state.runner.get_metadata(module) {
+    let runner = state.runner.load();
+    match runner.plugins.get(&name) {
+        Some(module) => match runner.get_metadata(module) {
             Ok(metadata) => Ok(Json(PluginMetadata {
                 name,
                 description: metadata.description,
                 parameters: metadata.parameters,
+                mode: metadata.mode.to_string(),
             })),
             Err(err) => {
                 tracing::error!("{err}");
@@ -99,29 +212,23 @@ async fn plugin_execute(
     Path((plugin, connection)): Path<(String, String)>,
     Json(variables): Json<HashMap<String, String>>,
 ) -> Result<Json<Value>, AppError> {
-    match state.runner.plugins.get(&plugin) {
-        Some(module) => match state.runner.connections.get(&connection) {
-            Some(conn) => match state.runner.get_metadata(module) {
+    // Held until the run ends, even if a reload swaps in a new one meanwhile.
+    let runner = state.runner.load_full();
+    match runner.plugins.get(&plugin) {
+        Some(module) => match runner.connections.get(&connection) {
+            Some(conn) => match runner.get_metadata(module) {
                 Ok(metadata) => {
                     let params = metadata.parameters;
-                    match parse_parameter_values(&params, &variables) {
-                        Ok(values) => match state.runner.run(conn, module, &values).await {
+                    match runner.parse_parameter_values(&params, &variables) {
+                        Ok(values) => match runner.run(conn, module, &values).await {
                             Ok(Some(qr)) => Ok(Json(qr.into())),
                             Ok(None) => Ok(Json(json!("no results returned"))),
                             Err(err) => {
                                 tracing::error!("{err}");
-                                Err(AppError::PluginExecution(
-                                    plugin,
-                                    connection,
-                                    err.to_string(),
-                                ))
+                                Err(AppError::Execution(plugin, connection, err))
                             }
                         },
-                        Err(err) => Err(AppError::ExecutionParameters(
-                            plugin,
-                            connection,
-                            err.to_string(),
-                        )),
+                        Err(err) => Err(AppError::Execution(plugin, connection, err)),
                     }
                 }
                 Err(err) => {
@@ -135,13 +242,40 @@ async fn plugin_execute(
     }
 }
 
+/// List the values a plugin parameter can take on a connection.
+async fn parameter_values(
+    State(state): State<Arc<AppState>>,
+    Path((plugin, connection, param)): Path<(String, String, String)>,
+) -> Result<Json<Vec<ParameterValue>>, AppError> {
+    let runner = state.runner.load_full();
+    match runner.parameter_values(&plugin, &connection, &param).await {
+        Ok(values) => Ok(Json(values)),
+        Err(err) => {
+            tracing::error!("{err}");
+            Err(AppError::Execution(plugin, connection, err))
+        }
+    }
+}
+
+/// Reload plugins and connections from disk.
+async fn reload(State(state): State<Arc<AppState>>) -> Result<Json<Reloaded>, AppError> {
+    match tokio::task::spawn_blocking(move || state.reload()).await {
+        Ok(Ok(reloaded)) => Ok(Json(reloaded)),
+        Ok(Err(err)) => {
+            tracing::error!("cannot reload, keeping the current plugins and connections: {err}");
+            Err(AppError::Reload(err.to_string()))
+        }
+        Err(err) => Err(AppError::Reload(err.to_string())),
+    }
+}
+
 /// Any error we may encounter.
 enum AppError {
     PluginMetadata,
     PluginMissing(String),
-    PluginExecution(String, String, String),
     ConnectionMissing(String),
-    ExecutionParameters(String, String, String),
+    Execution(String, String, QueryRunnerError),
+    Reload(String),
 }
 
 impl IntoResponse for AppError {
@@ -157,12 +291,44 @@ impl IntoResponse for AppError {
             AppError::PluginMissing(name) => {
                 (StatusCode::NOT_FOUND, format!("plugin `{name}` not found"))
             },
-            AppError::PluginExecution(plugin, connection, error) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("plugin `{plugin}` failed on connection `{connection}`: {error}"))
+            AppError::Reload(error) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, format!("reload failed, nothing changed: {error}"))
             },
-            AppError::ExecutionParameters(plugin, connection, error) => {
+            AppError::Execution(plugin, connection, QueryRunnerError::Killed(killed)) => {
+                let status = match killed {
+                    PluginKilled::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+                    PluginKilled::FuelExhausted(_) | PluginKilled::MemoryExceeded(_) => {
+                        StatusCode::UNPROCESSABLE_ENTITY
+                    }
+                };
+                (status, format!("plugin `{plugin}` was killed on connection `{connection}`: {killed}"))
+            },
+            AppError::Execution(plugin, connection, QueryRunnerError::Parameter(error)) => {
                 (StatusCode::BAD_REQUEST, format!("plugin `{plugin}` failed on connection `{connection}` while parsing parameters: {error}"))
             },
+            AppError::Execution(_, _, QueryRunnerError::PluginMissing(name)) => {
+                (StatusCode::NOT_FOUND, format!("plugin `{name}` not found"))
+            },
+            AppError::Execution(_, _, QueryRunnerError::ConnectionMissing(name)) => {
+                (StatusCode::NOT_FOUND, format!("connection `{name}` not found"))
+            },
+            AppError::Execution(plugin, _, QueryRunnerError::ParameterMissing(name)) => {
+                (StatusCode::NOT_FOUND, format!("parameter `{name}` of plugin `{plugin}` not found"))
+            },
+            AppError::Execution(plugin, connection, error) => {
+                let status = match error {
+                    // The database failed, not us.
+                    QueryRunnerError::Database(_) => StatusCode::BAD_GATEWAY,
+                    // The plugin refused the input or the data.
+                    QueryRunnerError::Plugin { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+                    // The connection does not allow what the plugin does.
+                    QueryRunnerError::ReadOnly => StatusCode::FORBIDDEN,
+                    // We failed, not the database.
+                    QueryRunnerError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (status, format!("plugin `{plugin}` failed on connection `{connection}`: {error}"))
+            },
         };
 
         let body = Json(json!({
@@ -193,4 +359,15 @@ struct PluginMetadata {
     name: String,
     description: String,
     parameters: Vec<Parameter>,
+    /// `read` or `write`.
+    mode: String,
+}
+
+/// What a reload changed.
+#[derive(Serialize)]
+struct Reloaded {
+    plugins: Changes,
+    connections: Changes,
+    /// Plugins whose settings changed.
+    settings: Changes,
 }
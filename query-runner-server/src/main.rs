@@ -1,5 +1,5 @@
 use anyhow::Result;
-use query_runner_server::app;
+use query_runner_server::watched_app;
 use std::net::SocketAddr;
 
 /// Web server entry point.
@@ -10,7 +10,7 @@ async fn main() -> Result<()> {
     let addr = SocketAddr::from(([127, 0, 0, 1], 4000));
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app()?.into_make_service())
+        .serve(watched_app()?.into_make_service())
         .await?;
     Ok(())
 }
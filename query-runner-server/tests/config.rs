@@ -1,7 +1,7 @@
 use anyhow::Result;
 use axum::{
     body::Body,
-    http::{Request, StatusCode},
+    http::{self, Request, StatusCode},
 };
 use pretty_assertions::assert_eq;
 use query_runner_server::app;
@@ -70,6 +70,14 @@ async fn plugins() -> Result<()> {
             {
                 "name": "test_collect2",
                 "description": "test plugin collecting results",
+            },
+            {
+                "name": "test_configured",
+                "description": "test plugin running the queries of its settings",
+            },
+            {
+                "name": "test_write",
+                "description": "test plugin running the statements of its settings",
             }
         ])
     );
@@ -131,10 +139,57 @@ async fn plugin_metadata() -> Result<()> {
                     {
                         "name": "customer_id",
                         "type": "integer",
+                        "required": true,
+                        "default": null,
+                        "description": "Customer whose orders to list",
+                        "constraints": {
+                            "min": null,
+                            "max": null,
+                            "max_length": null,
+                            "pattern": null,
+                            "allowed_values": [],
+                        },
+                        "values_query": "SELECT DISTINCT customer_id FROM Orders ORDER BY customer_id",
                     }
-                ]
+                ],
+                "mode": "read",
             }
         )
     );
     Ok(())
 }
+
+#[tokio::test]
+async fn reload() -> Result<()> {
+    let app = app()?;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/admin/reload")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    let unchanged = json!({
+        "added": [],
+        "changed": [],
+        "removed": [],
+    });
+    assert_eq!(
+        body,
+        json!({
+            "plugins": unchanged,
+            "connections": unchanged,
+            "settings": unchanged,
+        })
+    );
+    Ok(())
+}
@@ -40,6 +40,20 @@ async fn plugin_execute() -> Result<()> {
                     "name",
                     "email"
                 ,],
+                "columns": [
+                    {
+                        "name": "name",
+                        "type": "string",
+                        "nullable": null,
+                        "db_type": "text",
+                    },
+                    {
+                        "name": "email",
+                        "type": "string",
+                        "nullable": null,
+                        "db_type": "text",
+                    },
+                ],
                 "values": [
                     [
                         "John Doe",
@@ -156,3 +170,38 @@ async fn plugin_execute_missing_parameter() -> Result<()> {
     );
     Ok(())
 }
+
+#[tokio::test]
+async fn plugin_execute_plugin_error() -> Result<()> {
+    let app = app()?;
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method(http::Method::POST)
+                .uri("/plugins/test_collect/memory")
+                .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(
+                    serde_json::to_vec(&json!({
+                        "customer_id": "-1"
+                    }))
+                    .unwrap(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(
+        body,
+        json!(
+            {
+                "error": "plugin `test_collect` failed on connection `memory`: plugin error `invalid-input`: customer_id must be positive, got -1",
+            }
+        )
+    );
+    Ok(())
+}
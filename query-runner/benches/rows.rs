@@ -0,0 +1,77 @@
+//! Compare sending rows to a plugin one by one and in batches.
+
+use std::collections::HashMap;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use query_runner::{DBConnection, State};
+
+/// Number of rows the query returns.
+const ROWS: i64 = 10_000;
+
+/// Load the state with a table of orders, that `test_collect` lists in batches, and a view of it that
+/// `test_collect2` lists one by one, as it does not export `rows`.
+fn state() -> State {
+    let mut st = State::load_from_disk().unwrap();
+    st.settings.insert(
+        "test_collect2".to_string(),
+        HashMap::from([("users_table".to_string(), "OrderUsers".to_string())]),
+    );
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory").unwrap() {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (order_id INTEGER PRIMARY KEY, customer_id INTEGER NOT NULL)",
+            (),
+        )
+        .unwrap();
+        conn.execute(
+            "WITH RECURSIVE ids(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM ids WHERE id < ?1)
+            INSERT INTO Orders (order_id, customer_id) SELECT id, 123 FROM ids",
+            [ROWS],
+        )
+        .unwrap();
+        conn.execute(
+            "CREATE VIEW OrderUsers AS
+            SELECT order_id AS name, NULL AS email, customer_id AS username FROM Orders",
+            (),
+        )
+        .unwrap();
+    }
+    st
+}
+
+fn rows(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("rows");
+    group.sample_size(10);
+
+    let mut st = state();
+    let variables = HashMap::from([("user_name", "123")]);
+    group.bench_function("row", |b| {
+        b.to_async(&runtime).iter(|| async {
+            st.run_untyped("test_collect2", "memory", &variables)
+                .await
+                .unwrap()
+        })
+    });
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    for batch_size in [10, 100, 1_000] {
+        st.batch_size = batch_size;
+        let st = &st;
+        group.bench_with_input(
+            BenchmarkId::new("batch", batch_size),
+            &batch_size,
+            |b, _| {
+                b.to_async(&runtime).iter(|| async {
+                    st.run_untyped("test_collect", "memory", &variables)
+                        .await
+                        .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, rows);
+criterion_main!(benches);
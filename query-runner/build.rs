@@ -0,0 +1,53 @@
+//! Generate the bindings of the functions the host exposes to plugins, and of the ones plugins export.
+//!
+//! The bindings are generated with the fork of `wai-bindgen-gen-wasmer` in this repository rather
+//! than with `wai_bindgen_wasmer::export!` and `import!`: upstream, host functions taking strings or
+//! lists do not compile, plugins must export every function of the interface, and empty lists of
+//! records are given to plugins at a dangling address they then free. See its readme.
+
+use std::{env, fs, path::PathBuf};
+
+use wai_bindgen_gen_core::{wai_parser::Interface, Files, Generator};
+
+const HOST_WAI: &str = "query-host.wai";
+const QUERY_WAI: &str = "query.wai";
+
+/// Callbacks plugins do not have to export, see `query.wai`.
+const OPTIONAL_EXPORTS: &[&str] = &[
+    "execution::columns",
+    "execution::rows",
+    "execution::affected",
+    "execution::next",
+];
+
+/// Generate the bindings of an interface the host imports from plugins, or exports to them.
+fn generate(wai: &str, import: bool) -> String {
+    let iface = Interface::parse_file(wai).unwrap_or_else(|err| panic!("{err:?}"));
+    let mut gen = wai_bindgen_gen_wasmer::Opts {
+        optional_exports: OPTIONAL_EXPORTS.iter().map(|name| name.to_string()).collect(),
+        ..Default::default()
+    }
+    .build();
+    let mut files = Files::default();
+    if import {
+        gen.generate_all(&[iface], &[], &mut files);
+    } else {
+        gen.generate_all(&[], &[iface], &mut files);
+    }
+    let (_, contents) = files.iter().next().expect("no bindings generated");
+    std::str::from_utf8(contents)
+        .expect("bindings are not UTF-8")
+        .to_string()
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={HOST_WAI}");
+    println!("cargo:rerun-if-changed={QUERY_WAI}");
+    println!("cargo:rerun-if-changed=query-common.wai");
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+
+    fs::write(out.join("query_host.rs"), generate(HOST_WAI, false))
+        .expect("cannot write host bindings");
+    fs::write(out.join("query.rs"), generate(QUERY_WAI, true))
+        .expect("cannot write plugin bindings");
+}
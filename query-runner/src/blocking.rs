@@ -0,0 +1,88 @@
+//! Executions on Tokio's blocking thread pool.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::Poll;
+
+use futures_util::Stream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::error::{QueryRunnerError, Result};
+use crate::{QueryResult, ResultStream};
+
+/// Callback receiving each intermediate result of an execution.
+pub(crate) type Emit<'a> = &'a mut dyn FnMut(QueryResult) -> Result<()>;
+
+/// Run an execution on a blocking thread, so that plugin callbacks and host functions can block,
+/// and stream back the results it emits.
+pub(crate) fn spawn_execution<F>(execute: F) -> ResultStream<'static>
+where
+    F: FnOnce(Emit<'_>) -> Result<()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(1);
+    let handle = tokio::task::spawn_blocking(move || {
+        let res = execute(&mut |qr| {
+            tx.blocking_send(Ok(qr))
+                .map_err(|_| QueryRunnerError::Internal("result stream closed".into()))
+        });
+        if let Err(err) = res {
+            // The receiver may be gone already, nobody to report to then.
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+    Box::pin(BlockingResultStream {
+        rx,
+        handle: Some(handle),
+    })
+}
+
+/// Stream of the results sent back by the blocking thread running the execution.
+struct BlockingResultStream {
+    rx: mpsc::Receiver<Result<QueryResult>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Stream for BlockingResultStream {
+    type Item = Result<QueryResult>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        match this.rx.poll_recv(cx) {
+            Poll::Ready(Some(res)) => Poll::Ready(Some(res)),
+            // Channel closed, report a panic in the blocking thread if any.
+            Poll::Ready(None) => match &mut this.handle {
+                Some(handle) => match Pin::new(handle).poll(cx) {
+                    Poll::Ready(res) => {
+                        this.handle = None;
+                        Poll::Ready(
+                            res.err()
+                                .map(|err| Err(QueryRunnerError::Internal(err.to_string()))),
+                        )
+                    }
+                    Poll::Pending => Poll::Pending,
+                },
+                None => Poll::Ready(None),
+            },
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_execution_panic() {
+        let mut stream = spawn_execution(|_| panic!("execution failed"));
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, QueryRunnerError::Internal(_)), "{err}");
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,124 @@
+//! Cache of compiled plugins on disk.
+
+use std::{
+    fmt::Write,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use wasmer::{Module, Store, Target};
+
+use crate::error::{QueryRunnerError, Result};
+use crate::Compiler;
+
+/// Compiled plugins stored in a folder, under a key identifying their WASM code and how it was compiled,
+/// so that a plugin is only compiled again when it or the runtime changes.
+pub struct ModuleCache {
+    dir: PathBuf,
+    compiler: Compiler,
+}
+
+impl ModuleCache {
+    /// Cache plugins compiled with the given compiler in the given folder, created when needed.
+    pub fn new(dir: impl Into<PathBuf>, compiler: Compiler) -> Self {
+        ModuleCache {
+            dir: dir.into(),
+            compiler,
+        }
+    }
+
+    /// Get the module of a plugin from its WASM code, loading it from the cache if it holds a valid one,
+    /// else compiling it and caching the result.
+    /// Failing to use the cache is logged, the plugin is then compiled as if there was no cache.
+    pub fn load(&self, store: &Store, plugin: &str, wasm: &[u8]) -> Result<Module> {
+        let path = self
+            .dir
+            .join(format!("{}.bin", cache_key(wasm, self.compiler)));
+        match fs::read(&path) {
+            // Safety: the cache only holds modules serialized below, and their key covers the runtime
+            // and target they were compiled for. Anyone able to write to the cache folder can run code.
+            Ok(bytes) => match unsafe { Module::deserialize(store, bytes) } {
+                Ok(module) => {
+                    tracing::debug!(plugin, "loaded compiled plugin from {}", path.display());
+                    return Ok(module);
+                }
+                Err(err) => {
+                    tracing::warn!(plugin, "invalid compiled plugin {}: {err}", path.display())
+                }
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!(
+                plugin,
+                "cannot read compiled plugin {}: {err}",
+                path.display()
+            ),
+        }
+        let module = Module::new(store, wasm).map_err(|err| QueryRunnerError::PluginLoad {
+            plugin: plugin.to_string(),
+            message: err.to_string(),
+        })?;
+        match self.save(&module, &path) {
+            Ok(()) => tracing::debug!(plugin, "cached compiled plugin in {}", path.display()),
+            Err(err) => tracing::warn!(plugin, "cannot cache compiled plugin: {err}"),
+        }
+        Ok(module)
+    }
+
+    /// Serialize a compiled module to the given path of the cache.
+    fn save(
+        &self,
+        module: &Module,
+        path: &Path,
+    ) -> std::result::Result<(), Box<dyn std::error::Error>> {
+        fs::create_dir_all(&self.dir)?;
+        let bytes = module.serialize()?;
+        // Write then rename, so that a concurrent load never reads a partial module.
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, &bytes)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Key of a compiled module: hash of its WASM code, of the versions of the runtime and of this crate,
+/// which sets up the compiler and its middlewares, of the compiler and of the target.
+fn cache_key(wasm: &[u8], compiler: Compiler) -> String {
+    let compiler = compiler.to_string();
+    let target = Target::default().triple().to_string();
+    let mut hasher = Sha256::new();
+    hasher.update(wasm);
+    for part in [
+        wasmer::VERSION,
+        env!("CARGO_PKG_VERSION"),
+        &compiler,
+        &target,
+    ] {
+        // Separate the parts, so they can't run into each other.
+        hasher.update([0]);
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().iter().fold(String::new(), |mut key, b| {
+        // Writing to a string cannot fail.
+        let _ = write!(key, "{b:02x}");
+        key
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        let compiler = Compiler::default();
+        let key = cache_key(b"\0asm", compiler);
+        assert_eq!(64, key.len());
+        assert_eq!(key, cache_key(b"\0asm", compiler));
+        assert_ne!(key, cache_key(b"\0asm\x01", compiler));
+        for other in Compiler::ALL.iter().filter(|c| **c != compiler) {
+            assert_ne!(key, cache_key(b"\0asm", *other));
+        }
+    }
+}
@@ -0,0 +1,105 @@
+//! Compilers of plugins, built in with cargo features.
+
+use std::{fmt::Display, str::FromStr};
+
+use wasmer_compiler::CompilerConfig;
+#[cfg(feature = "cranelift")]
+use wasmer_compiler_cranelift::Cranelift;
+#[cfg(feature = "llvm")]
+use wasmer_compiler_llvm::LLVM;
+#[cfg(feature = "singlepass")]
+use wasmer_compiler_singlepass::Singlepass;
+
+use crate::error::QueryRunnerError;
+
+#[cfg(not(any(feature = "cranelift", feature = "llvm", feature = "singlepass")))]
+compile_error!("at least one of the `cranelift`, `llvm` or `singlepass` features must be enabled");
+
+/// Compiler turning plugins into native code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compiler {
+    /// Compiles quickly to fast code.
+    #[cfg(feature = "cranelift")]
+    Cranelift,
+    /// Compiles slowly to the fastest code, needs LLVM installed.
+    #[cfg(feature = "llvm")]
+    Llvm,
+    /// Compiles fastest to slower code.
+    #[cfg(feature = "singlepass")]
+    Singlepass,
+}
+
+impl Compiler {
+    /// The compilers built in, the default one first.
+    pub const ALL: &'static [Compiler] = &[
+        #[cfg(feature = "cranelift")]
+        Compiler::Cranelift,
+        #[cfg(feature = "llvm")]
+        Compiler::Llvm,
+        #[cfg(feature = "singlepass")]
+        Compiler::Singlepass,
+    ];
+
+    /// Default configuration of the compiler.
+    pub(crate) fn config(self) -> Box<dyn CompilerConfig> {
+        match self {
+            #[cfg(feature = "cranelift")]
+            Compiler::Cranelift => Box::new(Cranelift::default()),
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => Box::new(LLVM::default()),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => Box::new(Singlepass::default()),
+        }
+    }
+}
+
+/// Cranelift if built in.
+impl Default for Compiler {
+    fn default() -> Self {
+        Compiler::ALL[0]
+    }
+}
+
+impl Display for Compiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            #[cfg(feature = "cranelift")]
+            Compiler::Cranelift => write!(f, "cranelift"),
+            #[cfg(feature = "llvm")]
+            Compiler::Llvm => write!(f, "llvm"),
+            #[cfg(feature = "singlepass")]
+            Compiler::Singlepass => write!(f, "singlepass"),
+        }
+    }
+}
+
+impl FromStr for Compiler {
+    type Err = QueryRunnerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compiler::ALL
+            .iter()
+            .find(|c| c.to_string() == s)
+            .copied()
+            .ok_or_else(|| {
+                let all: Vec<String> = Compiler::ALL.iter().map(|c| c.to_string()).collect();
+                QueryRunnerError::Configuration(format!(
+                    "unknown compiler {s}, built in: {}",
+                    all.join(", ")
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_compiler() {
+        for compiler in Compiler::ALL {
+            assert_eq!(Ok(*compiler), compiler.to_string().parse().map_err(|_| ()));
+        }
+        assert!("javac".parse::<Compiler>().is_err());
+    }
+}
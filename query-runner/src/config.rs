@@ -1,52 +1,154 @@
 //! Configuration utilities.
 
 use std::fs::{self, File};
-use std::{collections::HashMap, io::BufReader};
+use std::{
+    collections::HashMap,
+    io::{BufReader, ErrorKind},
+};
 
-use anyhow::{anyhow, Result};
 use wasmer::{Module, Store};
 use wasmer_compiler::Engine;
 
+use crate::cache::ModuleCache;
+use crate::error::{QueryRunnerError, Result};
 use crate::DBConnection;
 
 /// Load connections from the given file.
 pub fn load_connections(path: &str) -> Result<HashMap<String, DBConnection>> {
-    let file = BufReader::new(File::open(path)?);
-    let values: HashMap<String, serde_yaml::Value> = serde_yaml::from_reader(file)?;
     let mut connections = HashMap::new();
-    for (name, value) in values.into_iter() {
-        let db = value
-            .get("db")
-            .ok_or(anyhow!("No db field"))?
-            .as_str()
-            .ok_or(anyhow!("db field is not a string"))?;
-        let conn = match db {
-            "sqlite" => crate::sqlite::new_connection(value)?,
-            "postgres" => crate::postgres::new_connection(value)?,
-            _ => return Err(anyhow!("unknown database type {db}")),
-        };
+    for (name, value) in read_connection_configs(path)?.into_iter() {
+        let conn = new_connection(&name, value)?;
         connections.insert(name, conn);
     }
     Ok(connections)
 }
 
+/// Read the configuration of each connection from the given file, by connection name.
+pub(crate) fn read_connection_configs(path: &str) -> Result<HashMap<String, serde_yaml::Value>> {
+    let file = BufReader::new(File::open(path).map_err(|err| {
+        QueryRunnerError::Configuration(format!("cannot open connections file {path}: {err}"))
+    })?);
+    serde_yaml::from_reader(file).map_err(|err| {
+        QueryRunnerError::Configuration(format!("invalid connections file {path}: {err}"))
+    })
+}
+
+/// Create a connection from its configuration.
+pub(crate) fn new_connection(name: &str, value: serde_yaml::Value) -> Result<DBConnection> {
+    let db = value
+        .get("db")
+        .ok_or(QueryRunnerError::Configuration(format!(
+            "No db field for connection {name}"
+        )))?
+        .as_str()
+        .ok_or(QueryRunnerError::Configuration(format!(
+            "db field is not a string for connection {name}"
+        )))?;
+    let read_only = match value.get("read_only") {
+        Some(read_only) => read_only
+            .as_bool()
+            .ok_or(QueryRunnerError::Configuration(format!(
+                "read_only field is not a boolean for connection {name}"
+            )))?,
+        None => false,
+    };
+    match db {
+        "sqlite" => crate::sqlite::new_connection(value, read_only),
+        "postgres" => crate::postgres::new_connection(value, read_only),
+        _ => Err(QueryRunnerError::Configuration(format!(
+            "unknown database type {db}"
+        ))),
+    }
+}
+
 /// Load plugins from the given folder.
 pub fn load_plugins(engine: &Engine, path: &str) -> Result<HashMap<String, Module>> {
-    let paths = fs::read_dir(path).unwrap();
+    read_plugins(engine, path, None)
+}
+
+/// Load plugins from the given folder, using the compiled modules of the cache when valid,
+/// and caching the ones that had to be compiled.
+pub fn load_cached_plugins(
+    engine: &Engine,
+    path: &str,
+    cache: &ModuleCache,
+) -> Result<HashMap<String, Module>> {
+    read_plugins(engine, path, Some(cache))
+}
+
+fn read_plugins(
+    engine: &Engine,
+    path: &str,
+    cache: Option<&ModuleCache>,
+) -> Result<HashMap<String, Module>> {
     let store = Store::new(engine);
     let mut plugins = HashMap::new();
+    for (name, wasm) in read_plugin_files(path)?.into_iter() {
+        let module = compile_plugin(&store, &name, &wasm, cache)?;
+        plugins.insert(name, module);
+    }
+    Ok(plugins)
+}
+
+/// Read the WASM code of each plugin from the given folder, by plugin name.
+pub(crate) fn read_plugin_files(path: &str) -> Result<HashMap<String, Vec<u8>>> {
+    let paths = fs::read_dir(path).map_err(|err| {
+        QueryRunnerError::Configuration(format!("cannot read plugins folder {path}: {err}"))
+    })?;
+    let mut plugins = HashMap::new();
     for path in paths {
-        let path = path?;
+        let path = path
+            .map_err(|err| QueryRunnerError::Configuration(format!("cannot read plugin: {err}")))?
+            .path();
 
-        let module = Module::from_file(&store, path.path())?;
         let name = path
-            .path()
             .file_stem()
-            .ok_or(anyhow!("no file name!"))?
-            .to_str()
-            .ok_or(anyhow!("Cannot get file name"))?
+            .and_then(|name| name.to_str())
+            .ok_or(QueryRunnerError::Configuration(format!(
+                "invalid plugin file name {}",
+                path.display()
+            )))?
             .to_owned();
-        plugins.insert(name, module);
+        let wasm = fs::read(&path).map_err(|err| QueryRunnerError::PluginLoad {
+            plugin: name.clone(),
+            message: err.to_string(),
+        })?;
+        plugins.insert(name, wasm);
     }
     Ok(plugins)
 }
+
+/// Compile the WASM code of a plugin, through the cache if any.
+pub(crate) fn compile_plugin(
+    store: &Store,
+    name: &str,
+    wasm: &[u8],
+    cache: Option<&ModuleCache>,
+) -> Result<Module> {
+    let mut module = match cache {
+        Some(cache) => cache.load(store, name, wasm)?,
+        None => Module::new(store, wasm).map_err(|err| QueryRunnerError::PluginLoad {
+            plugin: name.to_string(),
+            message: err.to_string(),
+        })?,
+    };
+    // Used to tag the plugin logs.
+    module.set_name(name);
+    Ok(module)
+}
+
+/// Load the settings of each plugin from the given file, if it exists.
+pub fn load_plugin_settings(path: &str) -> Result<HashMap<String, HashMap<String, String>>> {
+    let file = match File::open(path) {
+        Ok(file) => BufReader::new(file),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(err) => {
+            return Err(QueryRunnerError::Configuration(format!(
+                "cannot open plugins file {path}: {err}"
+            )))
+        }
+    };
+    serde_yaml::from_reader(file).map_err(|err| {
+        QueryRunnerError::Configuration(format!("invalid plugins file {path}: {err}"))
+    })
+}
@@ -0,0 +1,89 @@
+//! Error type.
+
+use thiserror::Error;
+use wasmer::RuntimeError;
+
+use crate::{PluginError, PluginKilled};
+
+/// Result type of the query runner.
+pub type Result<T, E = QueryRunnerError> = std::result::Result<T, E>;
+
+/// Errors of the query runner.
+#[derive(Debug, Error)]
+pub enum QueryRunnerError {
+    /// The connections file or a connection definition is invalid.
+    #[error("configuration error: {0}")]
+    Configuration(String),
+    /// A plugin module could not be read or compiled.
+    #[error("cannot load plugin `{plugin}`: {message}")]
+    PluginLoad { plugin: String, message: String },
+    /// No plugin is registered under the given name.
+    #[error("no plugin named {0} registered")]
+    PluginMissing(String),
+    /// No connection is registered under the given name.
+    #[error("no connection named {0} registered")]
+    ConnectionMissing(String),
+    /// A plugin module could not be instantiated.
+    #[error("cannot instantiate plugin: {0}")]
+    Instantiation(String),
+    /// A call into the plugin trapped.
+    #[error("plugin trapped: {0}")]
+    Trap(#[source] RuntimeError),
+    /// The plugin run was stopped for exceeding its limits.
+    #[error(transparent)]
+    Killed(#[from] PluginKilled),
+    /// The plugin reported an error.
+    #[error("plugin error `{code}`: {message}")]
+    Plugin { code: String, message: String },
+    /// The metadata of a plugin is invalid, like a constraint its parameter type cannot have.
+    #[error("invalid plugin metadata: {0}")]
+    Metadata(String),
+    /// A write plugin was run on a read-only connection.
+    #[error("the plugin writes to the database but the connection is read-only")]
+    ReadOnly,
+    /// The plugin has no parameter with the given name.
+    #[error("no parameter named {0}")]
+    ParameterMissing(String),
+    /// A parameter value is missing or invalid.
+    #[error("{0}")]
+    Parameter(String),
+    /// The query runner itself failed, like a thread running a plugin that panicked.
+    #[error("internal error: {0}")]
+    Internal(String),
+    /// The database failed to run the query or to convert its results.
+    #[error("database error: {0}")]
+    Database(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<RuntimeError> for QueryRunnerError {
+    fn from(err: RuntimeError) -> Self {
+        QueryRunnerError::Trap(err)
+    }
+}
+
+impl From<PluginError> for QueryRunnerError {
+    fn from(err: PluginError) -> Self {
+        QueryRunnerError::Plugin {
+            code: err.code,
+            message: err.message,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for QueryRunnerError {
+    fn from(err: rusqlite::Error) -> Self {
+        QueryRunnerError::Database(err.into())
+    }
+}
+
+impl From<tokio_postgres::Error> for QueryRunnerError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        QueryRunnerError::Database(err.into())
+    }
+}
+
+impl From<bb8::RunError<tokio_postgres::Error>> for QueryRunnerError {
+    fn from(err: bb8::RunError<tokio_postgres::Error>) -> Self {
+        QueryRunnerError::Database(err.into())
+    }
+}
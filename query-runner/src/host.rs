@@ -0,0 +1,256 @@
+//! Functions the host exposes to plugins.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use parking_lot::Mutex;
+
+use crate::error::Result;
+use crate::query;
+
+// Generated by build.rs, see there why `wai_bindgen_wasmer::export!` cannot be used.
+include!(concat!(env!("OUT_DIR"), "/query_host.rs"));
+
+pub(crate) use query_host::add_to_imports;
+use query_host::{LogLevel, PluginError};
+
+/// Runs the sub-queries of a plugin on the connection of its run.
+pub(crate) type SubQueryRunner =
+    Box<dyn FnMut(&str, &[query::VariableResult]) -> Result<query::QueryResult> + Send>;
+
+/// Sub-queries a plugin run can make.
+pub(crate) struct SubQueries {
+    /// Set by the connection while the run executes, `None` outside of it.
+    pub(crate) runner: Option<SubQueryRunner>,
+    /// Number of sub-queries made so far.
+    count: u32,
+    /// Maximum number of sub-queries, unlimited if `None`.
+    max: Option<u32>,
+}
+
+impl SubQueries {
+    pub(crate) fn new(max: Option<u32>) -> Self {
+        SubQueries {
+            runner: None,
+            count: 0,
+            max,
+        }
+    }
+}
+
+/// Host side of a plugin instance.
+pub(crate) struct Host {
+    /// Name of the plugin, to tag its logs.
+    pub(crate) plugin: String,
+    /// Settings of the plugin.
+    pub(crate) settings: HashMap<String, String>,
+    /// Shared with the execution, which gives it the connection to use.
+    pub(crate) sub_queries: Arc<Mutex<SubQueries>>,
+}
+
+impl query_host::QueryHost for Host {
+    fn log(&mut self, level: LogLevel, message: &str) {
+        let plugin = self.plugin.as_str();
+        match level {
+            LogLevel::Trace => tracing::trace!(plugin, "{message}"),
+            LogLevel::Debug => tracing::debug!(plugin, "{message}"),
+            LogLevel::Info => tracing::info!(plugin, "{message}"),
+            LogLevel::Warn => tracing::warn!(plugin, "{message}"),
+            LogLevel::Error => tracing::error!(plugin, "{message}"),
+        }
+    }
+
+    fn now(&mut self) -> i64 {
+        Utc::now().timestamp_micros()
+    }
+
+    fn config_get(&mut self, key: &str) -> Option<String> {
+        self.settings.get(key).cloned()
+    }
+
+    fn query(
+        &mut self,
+        query_string: &str,
+        variables: Vec<query_host::Variable<'_>>,
+    ) -> Result<query_host::QueryResult, PluginError> {
+        let mut sub_queries = self.sub_queries.lock();
+        let SubQueries { runner, count, max } = &mut *sub_queries;
+        let Some(runner) = runner else {
+            return Err(error(
+                "no-connection",
+                "queries can only be made from `row` or `end`",
+            ));
+        };
+        if let Some(max) = max.filter(|max| *count >= *max) {
+            return Err(error(
+                "too-many-queries",
+                format!("a run can make at most {max} queries"),
+            ));
+        }
+        *count += 1;
+        let variables: Vec<query::VariableResult> = variables
+            .iter()
+            .map(|v| query::VariableResult {
+                name: v.name.to_string(),
+                value: value_result(&v.value),
+            })
+            .collect();
+        tracing::debug!(plugin = self.plugin.as_str(), "sub-query: {query_string}");
+        match runner(query_string, &variables) {
+            Ok(qr) => Ok(query_host::QueryResult {
+                columns: qr.columns.into_iter().map(host_column).collect(),
+                values: qr
+                    .values
+                    .into_iter()
+                    .map(|row| row.into_iter().map(host_value).collect())
+                    .collect(),
+            }),
+            Err(err) => Err(error("query-failed", err.to_string())),
+        }
+    }
+}
+
+/// A plugin error with the given code and message.
+fn error(code: &str, message: impl Into<String>) -> PluginError {
+    PluginError {
+        code: code.to_string(),
+        message: message.into(),
+    }
+}
+
+/// Convert a value given by the plugin.
+fn value_result(value: &query_host::ValueParam<'_>) -> query::ValueResult {
+    use query::ValueResult as V;
+    use query_host::ValueParam as P;
+    match value {
+        P::DataString(s) => V::DataString(s.as_ref().map(|s| s.to_string())),
+        P::DataTimestamp(t) => V::DataTimestamp(t.map(|t| query::Timestamp {
+            micros: t.micros,
+            offset_seconds: t.offset_seconds,
+        })),
+        P::DataBoolean(b) => V::DataBoolean(*b),
+        P::DataInteger(i) => V::DataInteger(*i),
+        P::DataDecimal(d) => V::DataDecimal(*d),
+        P::DataDate(d) => V::DataDate(*d),
+        P::DataTime(t) => V::DataTime(*t),
+        P::DataNumeric(n) => V::DataNumeric(n.as_ref().map(|n| n.to_string())),
+        P::DataBytes(b) => V::DataBytes(b.as_ref().map(|b| b.to_vec())),
+        P::DataUuid(u) => V::DataUuid(u.as_ref().map(|u| u.to_string())),
+        P::DataJson(j) => V::DataJson(j.as_ref().map(|j| j.to_string())),
+        P::DataList(l) => V::DataList(l.as_ref().map(|l| l.iter().map(scalar_result).collect())),
+        P::DataRecord(r) => V::DataRecord(r.as_ref().map(|r| {
+            r.iter()
+                .map(|f| query::FieldResult {
+                    name: f.name.to_string(),
+                    value: scalar_result(&f.value),
+                })
+                .collect()
+        })),
+    }
+}
+
+/// Convert a scalar given by the plugin.
+fn scalar_result(scalar: &query_host::ScalarParam<'_>) -> query::ScalarResult {
+    use query::ScalarResult as S;
+    use query_host::ScalarParam as P;
+    match scalar {
+        P::ScalarNull => S::ScalarNull,
+        P::ScalarString(s) => S::ScalarString(s.to_string()),
+        P::ScalarTimestamp(t) => S::ScalarTimestamp(query::Timestamp {
+            micros: t.micros,
+            offset_seconds: t.offset_seconds,
+        }),
+        P::ScalarBoolean(b) => S::ScalarBoolean(*b),
+        P::ScalarInteger(i) => S::ScalarInteger(*i),
+        P::ScalarDecimal(d) => S::ScalarDecimal(*d),
+        P::ScalarDate(d) => S::ScalarDate(*d),
+        P::ScalarTime(t) => S::ScalarTime(*t),
+        P::ScalarNumeric(n) => S::ScalarNumeric(n.to_string()),
+        P::ScalarBytes(b) => S::ScalarBytes(b.to_vec()),
+        P::ScalarUuid(u) => S::ScalarUuid(u.to_string()),
+        P::ScalarJson(j) => S::ScalarJson(j.to_string()),
+    }
+}
+
+/// Convert a value to send back to the plugin.
+fn host_value(value: query::ValueResult) -> query_host::ValueResult {
+    use query::ValueResult as V;
+    use query_host::ValueResult as H;
+    match value {
+        V::DataString(s) => H::DataString(s),
+        V::DataTimestamp(t) => H::DataTimestamp(t.map(host_timestamp)),
+        V::DataBoolean(b) => H::DataBoolean(b),
+        V::DataInteger(i) => H::DataInteger(i),
+        V::DataDecimal(d) => H::DataDecimal(d),
+        V::DataDate(d) => H::DataDate(d),
+        V::DataTime(t) => H::DataTime(t),
+        V::DataNumeric(n) => H::DataNumeric(n),
+        V::DataBytes(b) => H::DataBytes(b),
+        V::DataUuid(u) => H::DataUuid(u),
+        V::DataJson(j) => H::DataJson(j),
+        V::DataList(l) => H::DataList(l.map(|l| l.into_iter().map(host_scalar).collect())),
+        V::DataRecord(r) => H::DataRecord(r.map(|r| {
+            r.into_iter()
+                .map(|f| query_host::FieldResult {
+                    name: f.name,
+                    value: host_scalar(f.value),
+                })
+                .collect()
+        })),
+    }
+}
+
+/// Convert a scalar to send back to the plugin.
+fn host_scalar(scalar: query::ScalarResult) -> query_host::ScalarResult {
+    use query::ScalarResult as S;
+    use query_host::ScalarResult as H;
+    match scalar {
+        S::ScalarNull => H::ScalarNull,
+        S::ScalarString(s) => H::ScalarString(s),
+        S::ScalarTimestamp(t) => H::ScalarTimestamp(host_timestamp(t)),
+        S::ScalarBoolean(b) => H::ScalarBoolean(b),
+        S::ScalarInteger(i) => H::ScalarInteger(i),
+        S::ScalarDecimal(d) => H::ScalarDecimal(d),
+        S::ScalarDate(d) => H::ScalarDate(d),
+        S::ScalarTime(t) => H::ScalarTime(t),
+        S::ScalarNumeric(n) => H::ScalarNumeric(n),
+        S::ScalarBytes(b) => H::ScalarBytes(b),
+        S::ScalarUuid(u) => H::ScalarUuid(u),
+        S::ScalarJson(j) => H::ScalarJson(j),
+    }
+}
+
+/// Convert a column description to send back to the plugin.
+fn host_column(column: query::ColumnResult) -> query_host::Column {
+    use query::ColumnType as C;
+    use query_host::ColumnType as H;
+    query_host::Column {
+        name: column.name,
+        column_type: match column.column_type {
+            C::TypeString => H::TypeString,
+            C::TypeTimestamp => H::TypeTimestamp,
+            C::TypeBoolean => H::TypeBoolean,
+            C::TypeInteger => H::TypeInteger,
+            C::TypeDecimal => H::TypeDecimal,
+            C::TypeDate => H::TypeDate,
+            C::TypeTime => H::TypeTime,
+            C::TypeNumeric => H::TypeNumeric,
+            C::TypeBytes => H::TypeBytes,
+            C::TypeUuid => H::TypeUuid,
+            C::TypeJson => H::TypeJson,
+            C::TypeList => H::TypeList,
+            C::TypeRecord => H::TypeRecord,
+            C::TypeUnknown => H::TypeUnknown,
+        },
+        nullable: column.nullable,
+        db_type: column.db_type,
+    }
+}
+
+fn host_timestamp(t: query::Timestamp) -> query_host::Timestamp {
+    query_host::Timestamp {
+        micros: t.micros,
+        offset_seconds: t.offset_seconds,
+    }
+}
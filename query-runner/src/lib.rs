@@ -2,23 +2,52 @@
 
 use serde::Serialize;
 use serde_yaml::with::singleton_map_recursive::serialize;
-use wasmer::{imports, Module, Store};
+use wasmer::{imports, wasmparser::Operator, Instance, Module, Store};
 use wasmer_compiler::*;
-use wasmer_compiler_llvm::LLVM;
 
-use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
 use serde_json::{json, Map, Value};
-use std::{collections::HashMap, fmt::Display, sync::Mutex};
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    pin::Pin,
+    sync::{atomic::AtomicBool, Arc},
+    time::Instant,
+};
 use tabled::builder::Builder;
 
+mod cache;
+pub use cache::ModuleCache;
+mod compiler;
+pub use compiler::Compiler;
 mod config;
-pub use config::{load_connections, load_plugins};
+pub use config::{load_cached_plugins, load_connections, load_plugin_settings, load_plugins};
+mod error;
+pub use error::{QueryRunnerError, Result};
+mod blocking;
+mod host;
+use host::{SubQueries, SubQueryRunner};
+mod limits;
+use limits::ModuleMetering;
+pub use limits::{ExecutionLimits, PluginKilled};
 mod parse;
-pub use parse::parse_parameter_values;
+use parse::Patterns;
+mod numeric;
 mod postgres;
+pub use postgres::PostgresPool;
+mod reload;
+use reload::Sources;
+pub use reload::{Changes, Reload};
 mod sqlite;
+pub use sqlite::SqlitePool;
+mod temporal;
 
-wai_bindgen_wasmer::import!("query.wai");
+// Generated by build.rs, see there why `wai_bindgen_wasmer::import!` is not used.
+include!(concat!(env!("OUT_DIR"), "/query.rs"));
 
 pub use crate::query::*;
 
@@ -28,8 +57,8 @@ const POSTGRES: &str = "postgres";
 
 /// Connections to databases.
 pub enum DBConnection {
-    SqliteConnection(Mutex<rusqlite::Connection>),
-    PostgresConnection(Box<::tokio_postgres::config::Config>),
+    SqliteConnection(Arc<SqlitePool>),
+    PostgresConnection(Box<PostgresPool>),
 }
 
 impl DBConnection {
@@ -41,19 +70,46 @@ impl DBConnection {
         }
     }
 
-    /// Execute the query against the DB and returns the result.
-    pub(crate) async fn execute(&self, state: &mut ExecutionState) -> Result<Option<QueryResult>> {
+    /// Whether the connection refuses write queries.
+    pub fn read_only(&self) -> bool {
         match self {
-            DBConnection::SqliteConnection(connection) => sqlite::execute(connection, state),
-            DBConnection::PostgresConnection(config) => {
-                crate::postgres::execute(config, state).await
+            DBConnection::SqliteConnection(pool) => pool.read_only,
+            DBConnection::PostgresConnection(pool) => pool.read_only,
+        }
+    }
+
+    /// Run a query of the host itself against the DB, returning all its rows.
+    pub(crate) async fn query(&self, query: &str) -> Result<QueryResult> {
+        match self {
+            DBConnection::SqliteConnection(pool) => sqlite::query(pool.clone(), query).await,
+            DBConnection::PostgresConnection(pool) => crate::postgres::query(pool, query).await,
+        }
+    }
+
+    /// Execute the query against the DB and returns the intermediate results as they come.
+    pub(crate) async fn execute_stream(&self, state: ExecutionState) -> Result<ResultStream<'_>> {
+        match self {
+            DBConnection::SqliteConnection(pool) => {
+                sqlite::execute_stream(pool.clone(), state).await
+            }
+            DBConnection::PostgresConnection(pool) => {
+                crate::postgres::execute_stream(pool, state).await
             }
         }
     }
 }
 
-/// An optional list of rows as result.
-//pub type QueryResult = Option<Vec<Vec<VariableResult>>>;
+/// A stream of intermediate results, in the order the plugin emits them.
+pub type ResultStream<'a> = Pin<Box<dyn Stream<Item = Result<QueryResult>> + Send + 'a>>;
+
+/// Collect the intermediate results of a run into one.
+async fn collect(mut stream: ResultStream<'_>) -> Result<Option<QueryResult>> {
+    let mut result = Option::None;
+    while let Some(res) = stream.next().await {
+        result = add_result(result, Some(res?));
+    }
+    Ok(result)
+}
 
 /// Add two results together.
 pub(crate) fn add_result(
@@ -72,25 +128,62 @@ pub(crate) fn add_result(
 
 /// Keep general engine state.
 pub struct State {
-    /// Connections by name.
-    pub connections: HashMap<String, DBConnection>,
+    /// Connections by name, shared with the states reloaded from this one.
+    pub connections: HashMap<String, Arc<DBConnection>>,
+    /// Compiler of the engine.
+    pub compiler: Compiler,
     /// WASM Engine.
     pub engine: Engine,
     /// Plugins by name.
     pub plugins: HashMap<String, Module>,
+    /// Limits applied to each plugin run.
+    pub limits: ExecutionLimits,
+    /// Settings of each plugin, by plugin name.
+    pub settings: HashMap<String, HashMap<String, String>>,
+    /// Number of rows sent at once to plugins taking them in batches.
+    pub batch_size: usize,
+    /// What plugins and connections were loaded from.
+    sources: Sources,
+    /// Patterns of the plugin parameters, compiled when the plugins are loaded.
+    patterns: Patterns,
 }
 
+/// File `load_from_disk` reads connections from.
+pub const CONNECTIONS_FILE: &str = "config/connections.yaml";
+
+/// Folder `load_from_disk` reads plugins from.
+pub const PLUGINS_FOLDER: &str = "plugins";
+
+/// File `load_from_disk` reads plugin settings from.
+pub const PLUGIN_SETTINGS_FILE: &str = "config/plugins.yaml";
+
+/// Folder where `load_from_disk` caches compiled plugins.
+pub const PLUGIN_CACHE: &str = "cache/plugins";
+
+/// Default number of rows sent at once to plugins taking them in batches.
+pub const DEFAULT_BATCH_SIZE: usize = 1_000;
+
 impl State {
-    /// Load state from local files.
+    /// Load state from local files, compiling plugins with the default compiler.
     pub fn load_from_disk() -> Result<State> {
-        let connections = load_connections("config/connections.yaml")?;
-        let engine = build_engine();
-        let plugins = load_plugins(&engine, "plugins")?;
-        Ok(State {
-            connections,
-            engine,
-            plugins,
-        })
+        State::load_from_disk_with(Compiler::default())
+    }
+
+    /// Load state from local files, compiling plugins with the given compiler.
+    pub fn load_from_disk_with(compiler: Compiler) -> Result<State> {
+        let empty = State {
+            connections: HashMap::new(),
+            compiler,
+            engine: build_engine_with(compiler),
+            plugins: HashMap::new(),
+            limits: ExecutionLimits::default(),
+            settings: HashMap::new(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            sources: Sources::default(),
+            patterns: Patterns::new(),
+        };
+        // Everything is new to an empty state.
+        Ok(empty.reload()?.state)
     }
 
     /// Run a plugin with untyped parameters.
@@ -101,9 +194,14 @@ impl State {
         variables: &HashMap<&str, &str>,
     ) -> Result<Option<QueryResult>> {
         let module = self.get_plugin(plugin)?;
-        let params = self.get_metadata(module)?.parameters;
-        let values = parse_parameter_values(&params, variables)?;
-        self.run_connection(connection, module, &values).await
+        let connection = self.get_connection(connection)?;
+        // The metadata of the instance running the plugin tells how to parse its parameters.
+        let (plugin, metadata) = self.instantiate_with_metadata(module)?;
+        let values = self.parse_parameter_values(&metadata.parameters, variables)?;
+        let stream = self
+            .stream_instance(connection, plugin, &metadata, &values)
+            .await?;
+        collect(stream).await
     }
 
     /// Run a plugin with typed parameters.
@@ -114,16 +212,6 @@ impl State {
         variables: &[VariableParam<'a>],
     ) -> Result<Option<QueryResult>> {
         let module = self.get_plugin(plugin)?;
-        self.run_connection(connection, module, variables).await
-    }
-
-    /// Run a module with the given variables.
-    async fn run_connection<'a>(
-        &'a self,
-        connection: &str,
-        module: &Module,
-        variables: &[VariableParam<'a>],
-    ) -> Result<Option<QueryResult>> {
         let connection = self.get_connection(connection)?;
         self.run(connection, module, variables).await
     }
@@ -135,50 +223,269 @@ impl State {
         module: &Module,
         variables: &[VariableParam<'a>],
     ) -> Result<Option<QueryResult>> {
-        let mut store = Store::new(&self.engine);
-        let mut imports = imports! {};
-        let (query, _instance) = Query::instantiate(&mut store, module, &mut imports)?;
-        let execution = query.start(&mut store, variables)?;
+        collect(self.run_stream(connection, module, variables).await?).await
+    }
+
+    /// Run a module knowing the connection and variables,
+    /// returning each intermediate result as soon as the plugin emits it.
+    pub async fn run_stream<'a>(
+        &self,
+        connection: &'a DBConnection,
+        module: &Module,
+        variables: &[VariableParam<'_>],
+    ) -> Result<ResultStream<'a>> {
+        let (plugin, metadata) = self.instantiate_with_metadata(module)?;
+        self.stream_instance(connection, plugin, &metadata, variables)
+            .await
+    }
 
-        let mut es = ExecutionState {
+    /// Run a plugin instance with the given metadata, returning each intermediate result as soon
+    /// as the plugin emits it. Write plugins are refused on read-only connections before they start.
+    async fn stream_instance<'a>(
+        &self,
+        connection: &'a DBConnection,
+        plugin: PluginInstance,
+        metadata: &QueryMetadata,
+        variables: &[VariableParam<'_>],
+    ) -> Result<ResultStream<'a>> {
+        if metadata.mode == QueryMode::Write && connection.read_only() {
+            return Err(QueryRunnerError::ReadOnly);
+        }
+        let es = self.start(plugin, metadata.mode, variables)?;
+        match (es.deadline, self.limits.timeout) {
+            (Some(deadline), Some(timeout)) => {
+                let stream =
+                    tokio::time::timeout_at(deadline.into(), connection.execute_stream(es))
+                        .await
+                        .map_err(|_| PluginKilled::Timeout(timeout))??;
+                Ok(limits::with_deadline(stream, deadline, timeout))
+            }
+            _ => connection.execute_stream(es).await,
+        }
+    }
+
+    /// Start an execution of a plugin instance with the given variables.
+    fn start(
+        &self,
+        plugin: PluginInstance,
+        mode: QueryMode,
+        variables: &[VariableParam<'_>],
+    ) -> Result<ExecutionState> {
+        let PluginInstance {
+            mut store,
+            query,
+            instance,
+            memory_exceeded,
+            sub_queries,
+            deadline,
+        } = plugin;
+        let execution = query.start(&mut store, variables).map_err(|err| {
+            self.limits
+                .check_trap(&mut store, &instance, &memory_exceeded, err)
+        })??;
+        let batch_size = query.has_execution_rows().then_some(self.batch_size.max(1));
+
+        Ok(ExecutionState {
             store,
             query,
             execution,
+            instance,
+            memory_exceeded,
+            sub_queries,
+            mode,
+            batch_size,
+            batch: Vec::new(),
+            limits: self.limits.clone(),
+            deadline,
+        })
+    }
+
+    /// Instantiate a module with the host functions and its full fuel budget.
+    /// The deadline of the run starts with the instantiation.
+    fn instantiate(&self, module: &Module) -> Result<PluginInstance> {
+        let deadline = self.limits.deadline();
+        let (mut store, memory_exceeded) = self.limits.store(&self.engine);
+        let plugin = module.name().unwrap_or_default();
+        let sub_queries = Arc::new(Mutex::new(SubQueries::new(self.limits.max_sub_queries)));
+        let host = host::Host {
+            plugin: plugin.to_string(),
+            settings: self.settings.get(plugin).cloned().unwrap_or_default(),
+            sub_queries: sub_queries.clone(),
         };
-        connection.execute(&mut es).await
+        let mut imports = imports! {};
+        let init_host = host::add_to_imports(&mut store, &mut imports, host);
+        let (query, instance) = Query::instantiate(&mut store, module, &mut imports)
+            .map_err(|err| self.limits.check_instantiation(&memory_exceeded, err))?;
+        init_host(&instance, &store)
+            .map_err(|err| QueryRunnerError::Instantiation(format!("{err:#}")))?;
+        self.limits.refuel(&mut store, &instance);
+        Ok(PluginInstance {
+            store,
+            query,
+            instance,
+            memory_exceeded,
+            sub_queries,
+            deadline,
+        })
+    }
+
+    /// Instantiate a module and get its metadata.
+    fn instantiate_with_metadata(
+        &self,
+        module: &Module,
+    ) -> Result<(PluginInstance, QueryMetadata)> {
+        let mut plugin = self.instantiate(module)?;
+        let metadata = plugin.query.metadata(&mut plugin.store).map_err(|err| {
+            self.limits.check_trap(
+                &mut plugin.store,
+                &plugin.instance,
+                &plugin.memory_exceeded,
+                err,
+            )
+        })?;
+        Ok((plugin, metadata))
+    }
+
+    /// Parse parameters given as strings, checking their constraints.
+    /// Missing parameters get their default value if they have one,
+    /// null if they are optional.
+    pub fn parse_parameter_values<'a, T>(
+        &self,
+        parameters: &'a [Parameter],
+        values: &'a HashMap<T, T>,
+    ) -> Result<Vec<VariableParam<'a>>>
+    where
+        T: Borrow<str> + Eq + Hash,
+    {
+        parse::parse_parameter_values(parameters, values, &self.patterns)
+    }
+
+    /// Check the parameters of every plugin and compile their patterns.
+    /// Plugins whose metadata cannot be read are skipped, running them fails the same way.
+    fn compile_patterns(&self) -> Result<Patterns> {
+        let mut patterns = Patterns::new();
+        for (name, module) in self.plugins.iter() {
+            if let Ok(metadata) = self.get_metadata(module) {
+                parse::compile_patterns(&metadata.parameters, &mut patterns).map_err(|err| {
+                    QueryRunnerError::PluginLoad {
+                        plugin: name.clone(),
+                        message: err.to_string(),
+                    }
+                })?;
+            }
+        }
+        Ok(patterns)
     }
 
     /// Get plugin module by name.
     pub fn get_plugin(&self, plugin: &str) -> Result<&Module> {
         self.plugins
             .get(plugin)
-            .ok_or(anyhow!("no plugin named {plugin} registered"))
+            .ok_or_else(|| QueryRunnerError::PluginMissing(plugin.to_string()))
     }
 
     /// Get metadata for a module.
     pub fn get_metadata(&self, module: &Module) -> Result<QueryMetadata> {
-        let mut store = Store::new(&self.engine);
-        let mut imports = imports! {};
-        let (query, _instance) = Query::instantiate(&mut store, module, &mut imports)?;
+        Ok(self.instantiate_with_metadata(module)?.1)
+    }
 
-        let metadata = query.metadata(&mut store)?;
-        Ok(metadata)
+    /// Get the values users can pick from for a parameter of a plugin,
+    /// running its values query on the given connection.
+    pub async fn parameter_values(
+        &self,
+        plugin: &str,
+        connection: &str,
+        parameter: &str,
+    ) -> Result<Vec<ParameterValue>> {
+        let module = self.get_plugin(plugin)?;
+        let connection = self.get_connection(connection)?;
+        let param = self
+            .get_metadata(module)?
+            .parameters
+            .into_iter()
+            .find(|p| p.name == parameter)
+            .ok_or_else(|| QueryRunnerError::ParameterMissing(parameter.to_string()))?;
+        let Some(values_query) = param.values_query else {
+            return Err(QueryRunnerError::Parameter(format!(
+                "parameter `{parameter}` has no values query"
+            )));
+        };
+        let result = connection.query(&values_query).await?;
+        Ok(parameter_values(result))
     }
 
     /// Get a connection by name.
     pub fn get_connection(&self, connection: &str) -> Result<&DBConnection> {
         self.connections
             .get(connection)
-            .ok_or(anyhow!("no connection named {connection} registered"))
+            .map(|conn| conn.as_ref())
+            .ok_or_else(|| QueryRunnerError::ConnectionMissing(connection.to_string()))
+    }
+}
+
+/// A value a parameter can take, with the label to show users.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ParameterValue {
+    pub value: String,
+    pub label: String,
+}
+
+/// Read parameter values from the first column of a values query,
+/// and their labels from the second one if any, skipping null values.
+fn parameter_values(result: QueryResult) -> Vec<ParameterValue> {
+    result
+        .values
+        .into_iter()
+        .filter_map(|row| {
+            let mut row = row.into_iter();
+            let value = row.next().and_then(string_value)?;
+            let label = row
+                .next()
+                .and_then(string_value)
+                .unwrap_or_else(|| value.clone());
+            Some(ParameterValue { value, label })
+        })
+        .collect()
+}
+
+/// A value in the format parameters are given in, none if null.
+fn string_value(value: ValueResult) -> Option<String> {
+    match Value::from(value.clone()) {
+        Value::Null => None,
+        _ => Some(value.to_string()),
     }
 }
 
-/// Build a new WASM engine.
+/// Build a new WASM engine with the default compiler.
 pub fn build_engine() -> Engine {
-    let compiler_config = LLVM::default();
+    build_engine_with(Compiler::default())
+}
+
+/// Build a new WASM engine with the given compiler.
+/// Compiled modules are metered so that runs can be given a fuel budget.
+/// Engines are only built by `State` and `build_engine`, see `ModuleMetering`.
+pub(crate) fn build_engine_with(compiler: Compiler) -> Engine {
+    let mut compiler_config = compiler.config();
+    compiler_config.push_middleware(Arc::new(ModuleMetering::new(operator_cost)));
     EngineBuilder::new(compiler_config).engine()
 }
 
+/// Fuel cost of a WASM operator: one point per instruction.
+fn operator_cost(_operator: &Operator) -> u64 {
+    1
+}
+
+/// A freshly instantiated plugin.
+struct PluginInstance {
+    store: Store,
+    query: Query,
+    instance: Instance,
+    memory_exceeded: Arc<AtomicBool>,
+    sub_queries: Arc<Mutex<SubQueries>>,
+    /// When a run of this instance must be finished.
+    deadline: Option<Instant>,
+}
+
 /// Stores everything related to one plugin execution.
 pub(crate) struct ExecutionState {
     /// The store.
@@ -187,16 +494,179 @@ pub(crate) struct ExecutionState {
     pub(crate) query: Query,
     /// The actual execution.
     pub(crate) execution: Execution,
+    /// The plugin instance.
+    pub(crate) instance: Instance,
+    /// Raised when the plugin tries to go over its memory limit.
+    pub(crate) memory_exceeded: Arc<AtomicBool>,
+    /// The queries the plugin makes itself, shared with its host functions.
+    pub(crate) sub_queries: Arc<Mutex<SubQueries>>,
+    /// Whether the plugin reads or writes.
+    pub(crate) mode: QueryMode,
+    /// Number of rows to send at once, if the plugin takes them in batches.
+    pub(crate) batch_size: Option<usize>,
+    /// Rows waiting to be sent in a batch.
+    pub(crate) batch: Vec<Vec<ValueResult>>,
+    /// The limits of this run.
+    pub(crate) limits: ExecutionLimits,
+    /// When this run must be finished.
+    pub(crate) deadline: Option<Instant>,
 }
 
 impl ExecutionState {
-    /// Send a row to the execution.
-    fn row(&mut self, row: Vec<Variable>) -> Result<Option<QueryResult>> {
-        let params: Vec<VariableParam<'_>> = row.iter().map(Variable::as_param).collect();
-        let r = self
-            .query
-            .execution_row(&mut self.store, &self.execution, &params)?;
-        Ok(r)
+    /// Let the plugin run its own queries through the given runner, or stop it with `None`.
+    pub(crate) fn set_sub_query_runner(&self, runner: Option<SubQueryRunner>) {
+        self.sub_queries.lock().runner = runner;
+    }
+
+    /// Get the query SQL.
+    pub(crate) fn query_string(&mut self) -> Result<String> {
+        self.limits.check_deadline(self.deadline)?;
+        self.query
+            .execution_query_string(&mut self.store, &self.execution)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })
+    }
+
+    /// Get the query parameters.
+    pub(crate) fn variables(&mut self) -> Result<Vec<VariableResult>> {
+        self.limits.check_deadline(self.deadline)?;
+        self.query
+            .execution_variables(&mut self.store, &self.execution)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })
+    }
+
+    /// Describe the columns of a query before its rows, if the execution takes them in batches
+    /// and wants them.
+    pub(crate) fn columns(&mut self, columns: &[ColumnResult]) -> Result<()> {
+        if self.batch_size.is_none() || !self.query.has_execution_columns() {
+            return Ok(());
+        }
+        self.limits.check_deadline(self.deadline)?;
+        let columns: Vec<ColumnParam<'_>> = columns.iter().map(ColumnResult::as_param).collect();
+        self.query
+            .execution_columns(&mut self.store, &self.execution, &columns)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })?
+            .map_err(Into::into)
+    }
+
+    /// Send a row to the execution, or add it to the current batch if it takes rows in batches,
+    /// sending the batch once full.
+    /// A plugin error aborts the query: no more rows must be sent, nor the end.
+    pub(crate) fn row(&mut self, row: Vec<Variable>) -> Result<Option<QueryResult>> {
+        let Some(batch_size) = self.batch_size else {
+            return self.send_row(row);
+        };
+        self.batch.push(row.into_iter().map(|v| v.value).collect());
+        if self.batch.len() < batch_size {
+            return Ok(None);
+        }
+        self.flush_rows()
+    }
+
+    /// Send the rows left in the current batch, if any, before the end of the query.
+    pub(crate) fn flush_rows(&mut self) -> Result<Option<QueryResult>> {
+        if self.batch.is_empty() {
+            return Ok(None);
+        }
+        self.limits.check_deadline(self.deadline)?;
+        let mut batch = std::mem::take(&mut self.batch);
+        let res = {
+            let elements: Vec<Vec<Elements<'_>>> = batch
+                .iter()
+                .map(|row| row.iter().map(Elements::of).collect())
+                .collect();
+            let rows: Vec<Vec<ValueParam<'_>>> = batch
+                .iter()
+                .zip(elements.iter())
+                .map(|(row, elements)| {
+                    row.iter()
+                        .zip(elements.iter())
+                        .map(|(v, e)| value_param(v, e))
+                        .collect()
+                })
+                .collect();
+            let rows: Vec<&[ValueParam<'_>]> = rows.iter().map(Vec::as_slice).collect();
+            self.query
+                .execution_rows(&mut self.store, &self.execution, &rows)
+        };
+        // Keep the allocation for the next batch.
+        batch.clear();
+        self.batch = batch;
+        res.map_err(|err| {
+            self.limits
+                .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+        })?
+        .map_err(Into::into)
+    }
+
+    /// Send a single row to the execution.
+    fn send_row(&mut self, row: Vec<Variable>) -> Result<Option<QueryResult>> {
+        self.limits.check_deadline(self.deadline)?;
+        let elements: Vec<Elements<'_>> = row.iter().map(|v| Elements::of(&v.value)).collect();
+        let params: Vec<VariableParam<'_>> = row
+            .iter()
+            .zip(elements.iter())
+            .map(|(v, e)| v.as_param(e))
+            .collect();
+        self.query
+            .execution_row(&mut self.store, &self.execution, &params)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })?
+            .map_err(Into::into)
+    }
+
+    /// Send the number of rows a write query changed to the execution, if it wants it.
+    /// A plugin error aborts the query and rolls its transaction back.
+    pub(crate) fn affected(&mut self, count: u64) -> Result<Option<QueryResult>> {
+        if !self.query.has_execution_affected() {
+            return Ok(None);
+        }
+        self.limits.check_deadline(self.deadline)?;
+        self.query
+            .execution_affected(&mut self.store, &self.execution, count)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })?
+            .map_err(Into::into)
+    }
+
+    /// Signal the end of the data to the execution, describing its columns.
+    pub(crate) fn end(&mut self, columns: &[ColumnResult]) -> Result<Option<QueryResult>> {
+        self.limits.check_deadline(self.deadline)?;
+        let columns: Vec<ColumnParam<'_>> = columns.iter().map(ColumnResult::as_param).collect();
+        self.query
+            .execution_end(&mut self.store, &self.execution, &columns)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })?
+            .map_err(Into::into)
+    }
+
+    /// Get the query to run next, if any: none if the execution does not chain queries.
+    pub(crate) fn next(&mut self) -> Result<Option<NextQuery>> {
+        if !self.query.has_execution_next() {
+            return Ok(None);
+        }
+        self.limits.check_deadline(self.deadline)?;
+        self.query
+            .execution_next(&mut self.store, &self.execution)
+            .map_err(|err| {
+                self.limits
+                    .check_trap(&mut self.store, &self.instance, &self.memory_exceeded, err)
+            })?
+            .map_err(Into::into)
     }
 }
 
@@ -207,22 +677,129 @@ pub(crate) struct Variable<'a> {
     pub(crate) value: ValueResult,
 }
 
+/// Elements of list and record values, that a `ValueParam` can only reference.
+enum Elements<'a> {
+    Scalars(Vec<ScalarParam<'a>>),
+    Fields(Vec<FieldParam<'a>>),
+    None,
+}
+
+impl<'a> Elements<'a> {
+    /// Borrow the elements of a value, if any.
+    fn of(value: &'a ValueResult) -> Self {
+        match value {
+            ValueResult::DataList(Some(l)) => {
+                Elements::Scalars(l.iter().map(ScalarResult::as_param).collect())
+            }
+            ValueResult::DataRecord(Some(r)) => Elements::Fields(
+                r.iter()
+                    .map(|f| FieldParam {
+                        name: &f.name,
+                        value: f.value.as_param(),
+                    })
+                    .collect(),
+            ),
+            _ => Elements::None,
+        }
+    }
+
+    fn scalars(&self) -> &[ScalarParam<'a>] {
+        match self {
+            Elements::Scalars(s) => s,
+            _ => &[],
+        }
+    }
+
+    fn fields(&self) -> &[FieldParam<'a>] {
+        match self {
+            Elements::Fields(f) => f,
+            _ => &[],
+        }
+    }
+}
+
+impl ScalarResult {
+    /// Converts a `ScalarResult` to a `ScalarParam`, referencing its data.
+    fn as_param(&self) -> ScalarParam<'_> {
+        match self {
+            ScalarResult::ScalarNull => ScalarParam::ScalarNull,
+            ScalarResult::ScalarString(s) => ScalarParam::ScalarString(s),
+            ScalarResult::ScalarTimestamp(t) => ScalarParam::ScalarTimestamp(*t),
+            ScalarResult::ScalarBoolean(b) => ScalarParam::ScalarBoolean(*b),
+            ScalarResult::ScalarInteger(i) => ScalarParam::ScalarInteger(*i),
+            ScalarResult::ScalarDecimal(d) => ScalarParam::ScalarDecimal(*d),
+            ScalarResult::ScalarDate(d) => ScalarParam::ScalarDate(*d),
+            ScalarResult::ScalarTime(t) => ScalarParam::ScalarTime(*t),
+            ScalarResult::ScalarNumeric(n) => ScalarParam::ScalarNumeric(n),
+            ScalarResult::ScalarBytes(b) => ScalarParam::ScalarBytes(b),
+            ScalarResult::ScalarUuid(u) => ScalarParam::ScalarUuid(u),
+            ScalarResult::ScalarJson(j) => ScalarParam::ScalarJson(j),
+        }
+    }
+}
+
+impl From<ScalarResult> for ValueResult {
+    fn from(sr: ScalarResult) -> Self {
+        match sr {
+            ScalarResult::ScalarNull => ValueResult::DataString(None),
+            ScalarResult::ScalarString(s) => ValueResult::DataString(Some(s)),
+            ScalarResult::ScalarTimestamp(t) => ValueResult::DataTimestamp(Some(t)),
+            ScalarResult::ScalarBoolean(b) => ValueResult::DataBoolean(Some(b)),
+            ScalarResult::ScalarInteger(i) => ValueResult::DataInteger(Some(i)),
+            ScalarResult::ScalarDecimal(d) => ValueResult::DataDecimal(Some(d)),
+            ScalarResult::ScalarDate(d) => ValueResult::DataDate(Some(d)),
+            ScalarResult::ScalarTime(t) => ValueResult::DataTime(Some(t)),
+            ScalarResult::ScalarNumeric(n) => ValueResult::DataNumeric(Some(n)),
+            ScalarResult::ScalarBytes(b) => ValueResult::DataBytes(Some(b)),
+            ScalarResult::ScalarUuid(u) => ValueResult::DataUuid(Some(u)),
+            ScalarResult::ScalarJson(j) => ValueResult::DataJson(Some(j)),
+        }
+    }
+}
+
+impl ColumnResult {
+    /// Converts a `ColumnResult` to a `ColumnParam`, referencing its data.
+    fn as_param(&self) -> ColumnParam<'_> {
+        ColumnParam {
+            name: &self.name,
+            column_type: self.column_type,
+            nullable: self.nullable,
+            db_type: self.db_type.as_deref(),
+        }
+    }
+}
+
 impl<'a> Variable<'a> {
-    /// Converts a Variable to a `VariableParam`, referencing the `Variable` data.
-    fn as_param(&'a self) -> VariableParam<'a> {
+    /// Converts a Variable to a `VariableParam`, referencing the `Variable` data
+    /// and the elements borrowed from it.
+    fn as_param(&'a self, elements: &'a Elements<'a>) -> VariableParam<'a> {
         VariableParam {
             name: self.name,
-            value: match &self.value {
-                ValueResult::DataBoolean(b) => ValueParam::DataBoolean(*b),
-                ValueResult::DataDecimal(d) => ValueParam::DataDecimal(*d),
-                ValueResult::DataInteger(i) => ValueParam::DataInteger(*i),
-                ValueResult::DataString(s) => ValueParam::DataString(s.as_deref()),
-                ValueResult::DataTimestamp(t) => ValueParam::DataTimestamp(t.as_deref()),
-            },
+            value: value_param(&self.value, elements),
         }
     }
 }
 
+/// Converts a `ValueResult` to a `ValueParam`, referencing its data
+/// and the elements borrowed from it.
+fn value_param<'a>(value: &'a ValueResult, elements: &'a Elements<'a>) -> ValueParam<'a> {
+    match value {
+        ValueResult::DataBoolean(b) => ValueParam::DataBoolean(*b),
+        ValueResult::DataDecimal(d) => ValueParam::DataDecimal(*d),
+        ValueResult::DataInteger(i) => ValueParam::DataInteger(*i),
+        ValueResult::DataString(s) => ValueParam::DataString(s.as_deref()),
+        ValueResult::DataTimestamp(t) => ValueParam::DataTimestamp(*t),
+        ValueResult::DataDate(d) => ValueParam::DataDate(*d),
+        ValueResult::DataTime(t) => ValueParam::DataTime(*t),
+        ValueResult::DataNumeric(n) => ValueParam::DataNumeric(n.as_deref()),
+        ValueResult::DataBytes(b) => ValueParam::DataBytes(b.as_deref()),
+        ValueResult::DataUuid(u) => ValueParam::DataUuid(u.as_deref()),
+        ValueResult::DataJson(j) => ValueParam::DataJson(j.as_deref()),
+        ValueResult::DataList(l) => ValueParam::DataList(l.as_ref().map(|_| elements.scalars())),
+        ValueResult::DataRecord(r) => ValueParam::DataRecord(r.as_ref().map(|_| elements.fields())),
+    }
+}
+
 impl Display for ValueResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -235,7 +812,46 @@ impl Display for ValueResult {
             ValueResult::DataString(None) => write!(f, "<null>"),
             ValueResult::DataString(Some(s)) => write!(f, "{s}"),
             ValueResult::DataTimestamp(None) => write!(f, "<null>"),
-            ValueResult::DataTimestamp(Some(s)) => write!(f, "{s}"),
+            ValueResult::DataTimestamp(Some(t)) => write!(f, "{t}"),
+            ValueResult::DataDate(None) => write!(f, "<null>"),
+            ValueResult::DataDate(Some(d)) => write!(f, "{}", temporal::format_date(*d)),
+            ValueResult::DataTime(None) => write!(f, "<null>"),
+            ValueResult::DataTime(Some(t)) => write!(f, "{}", temporal::format_time(*t)),
+            ValueResult::DataNumeric(None) => write!(f, "<null>"),
+            ValueResult::DataNumeric(Some(n)) => write!(f, "{n}"),
+            ValueResult::DataBytes(None) => write!(f, "<null>"),
+            ValueResult::DataBytes(Some(b)) => write!(f, "{}", STANDARD.encode(b)),
+            ValueResult::DataUuid(None) => write!(f, "<null>"),
+            ValueResult::DataUuid(Some(u)) => write!(f, "{u}"),
+            ValueResult::DataJson(None) => write!(f, "<null>"),
+            ValueResult::DataJson(Some(j)) => write!(f, "{j}"),
+            ValueResult::DataList(None) => write!(f, "<null>"),
+            ValueResult::DataList(Some(l)) => {
+                write!(f, "[")?;
+                for (ix, s) in l.iter().enumerate() {
+                    if ix > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", ValueResult::from(s.clone()))?;
+                }
+                write!(f, "]")
+            }
+            ValueResult::DataRecord(None) => write!(f, "<null>"),
+            ValueResult::DataRecord(Some(r)) => {
+                write!(f, "{{")?;
+                for (ix, field) in r.iter().enumerate() {
+                    if ix > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(
+                        f,
+                        "{}: {}",
+                        field.name,
+                        ValueResult::from(field.value.clone())
+                    )?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -243,7 +859,7 @@ impl Display for ValueResult {
 /// Format result as a table.
 pub fn table_result(qr: &QueryResult) -> String {
     let mut builder = Builder::default();
-    builder.set_header(&qr.names);
+    builder.set_header(qr.columns.iter().map(|c| &c.name));
     for row in qr.values.iter() {
         builder.push_record(row.iter().map(|r| format!("{r}")));
     }
@@ -259,6 +875,41 @@ impl Display for ParameterType {
             ParameterType::TypeInteger => write!(f, "integer"),
             ParameterType::TypeString => write!(f, "string"),
             ParameterType::TypeTimestamp => write!(f, "timestamp"),
+            ParameterType::TypeDate => write!(f, "date"),
+            ParameterType::TypeTime => write!(f, "time"),
+            ParameterType::TypeNumeric => write!(f, "numeric"),
+            ParameterType::TypeUuid => write!(f, "uuid"),
+            ParameterType::TypeJson => write!(f, "json"),
+        }
+    }
+}
+
+impl Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColumnType::TypeString => write!(f, "string"),
+            ColumnType::TypeTimestamp => write!(f, "timestamp"),
+            ColumnType::TypeBoolean => write!(f, "bool"),
+            ColumnType::TypeInteger => write!(f, "integer"),
+            ColumnType::TypeDecimal => write!(f, "decimal"),
+            ColumnType::TypeDate => write!(f, "date"),
+            ColumnType::TypeTime => write!(f, "time"),
+            ColumnType::TypeNumeric => write!(f, "numeric"),
+            ColumnType::TypeBytes => write!(f, "bytes"),
+            ColumnType::TypeUuid => write!(f, "uuid"),
+            ColumnType::TypeJson => write!(f, "json"),
+            ColumnType::TypeList => write!(f, "list"),
+            ColumnType::TypeRecord => write!(f, "record"),
+            ColumnType::TypeUnknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Display for QueryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryMode::Read => write!(f, "read"),
+            QueryMode::Write => write!(f, "write"),
         }
     }
 }
@@ -270,7 +921,23 @@ impl From<ValueResult> for Value {
             ValueResult::DataDecimal(Some(d)) => json!(d),
             ValueResult::DataInteger(Some(i)) => json!(i),
             ValueResult::DataString(Some(s)) => Value::String(s),
-            ValueResult::DataTimestamp(Some(t)) => Value::String(t),
+            ValueResult::DataTimestamp(Some(t)) => Value::String(t.to_string()),
+            ValueResult::DataDate(Some(d)) => Value::String(temporal::format_date(d)),
+            ValueResult::DataTime(Some(t)) => Value::String(temporal::format_time(t)),
+            // Kept as a string, a JSON number would go through f64.
+            ValueResult::DataNumeric(Some(n)) => Value::String(n),
+            ValueResult::DataBytes(Some(b)) => Value::String(STANDARD.encode(b)),
+            ValueResult::DataUuid(Some(u)) => Value::String(u),
+            // Nested as is, unless the database let through invalid JSON.
+            ValueResult::DataJson(Some(j)) => serde_json::from_str(&j).unwrap_or(Value::String(j)),
+            ValueResult::DataList(Some(l)) => {
+                Value::Array(l.into_iter().map(|s| ValueResult::from(s).into()).collect())
+            }
+            ValueResult::DataRecord(Some(r)) => Value::Object(
+                r.into_iter()
+                    .map(|f| (f.name, ValueResult::from(f.value).into()))
+                    .collect(),
+            ),
             _ => Value::Null,
         }
     }
@@ -281,7 +948,11 @@ impl From<QueryResult> for Value {
         let mut map = Map::new();
         map.insert(
             "names".to_string(),
-            Value::Array(qr.names.into_iter().map(|s| s.into()).collect()),
+            Value::Array(qr.columns.iter().map(|c| c.name.clone().into()).collect()),
+        );
+        map.insert(
+            "columns".to_string(),
+            Value::Array(qr.columns.into_iter().map(|c| c.into()).collect()),
         );
         let mut rows = Vec::new();
         for row in qr.values.into_iter() {
@@ -293,11 +964,27 @@ impl From<QueryResult> for Value {
     }
 }
 
+impl From<ColumnResult> for Value {
+    fn from(c: ColumnResult) -> Self {
+        json!({
+            "name": c.name,
+            "type": c.column_type.to_string(),
+            "nullable": c.nullable,
+            "db_type": c.db_type,
+        })
+    }
+}
+
 impl From<Parameter> for Value {
     fn from(p: Parameter) -> Self {
         json!({
             "name": p.name,
             "type": p.parameter_type.to_string(),
+            "required": p.required,
+            "default": p.default_value,
+            "description": p.description,
+            "constraints": Value::from(&p.constraints),
+            "values_query": p.values_query,
         })
     }
 }
@@ -311,6 +998,11 @@ impl Serialize for Parameter {
             &json!({
                 "name": self.name,
                 "type": self.parameter_type.to_string(),
+                "required": self.required,
+                "default": self.default_value,
+                "description": self.description,
+                "constraints": Value::from(&self.constraints),
+                "values_query": self.values_query,
             }),
             serializer,
         )
@@ -319,8 +1011,51 @@ impl Serialize for Parameter {
 
 impl PartialEq for Parameter {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name && self.parameter_type == other.parameter_type
+        self.name == other.name
+            && self.parameter_type == other.parameter_type
+            && self.required == other.required
+            && self.default_value == other.default_value
+            && self.description == other.description
+            && self.constraints == other.constraints
+            && self.values_query == other.values_query
     }
 }
 
 impl Eq for Parameter {}
+
+/// No constraints.
+impl Default for ParameterConstraints {
+    fn default() -> Self {
+        ParameterConstraints {
+            min: None,
+            max: None,
+            max_length: None,
+            pattern: None,
+            allowed_values: Vec::new(),
+        }
+    }
+}
+
+impl PartialEq for ParameterConstraints {
+    fn eq(&self, other: &Self) -> bool {
+        self.min == other.min
+            && self.max == other.max
+            && self.max_length == other.max_length
+            && self.pattern == other.pattern
+            && self.allowed_values == other.allowed_values
+    }
+}
+
+impl Eq for ParameterConstraints {}
+
+impl From<&ParameterConstraints> for Value {
+    fn from(c: &ParameterConstraints) -> Self {
+        json!({
+            "min": c.min,
+            "max": c.max,
+            "max_length": c.max_length,
+            "pattern": c.pattern,
+            "allowed_values": c.allowed_values,
+        })
+    }
+}
@@ -0,0 +1,536 @@
+//! Resource limits for plugin executions.
+
+use std::{
+    fmt::Display,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::StreamExt;
+use parking_lot::Mutex;
+use wasmer::{
+    vm::{
+        MemoryError, MemoryStyle, TableStyle, VMMemory, VMMemoryDefinition, VMTable,
+        VMTableDefinition,
+    },
+    wasmparser::Operator,
+    BaseTunables, FunctionMiddleware, Instance, LocalFunctionIndex, MemoryType, ModuleMiddleware,
+    Pages, RuntimeError, Store, TableType, Target, Tunables,
+};
+use wasmer_compiler::Engine;
+use wasmer_middlewares::{
+    metering::{get_remaining_points, set_remaining_points, MeteringPoints},
+    Metering,
+};
+use wasmer_types::ModuleInfo;
+use wasmer_vm::LinearMemory;
+
+use crate::error::{QueryRunnerError, Result};
+use crate::ResultStream;
+
+/// Fuel given to runs with a timeout but no fuel limit, for each millisecond of the timeout.
+/// Plugins execute fewer instructions than that in practice, so it only stops runs past their
+/// deadline.
+const FUEL_PER_MILLISECOND: u64 = 1_000_000;
+
+/// Limits applied to each plugin run.
+#[derive(Debug, Clone, Default)]
+pub struct ExecutionLimits {
+    /// Maximum number of WASM instructions a run can execute, unlimited if `None`.
+    pub fuel: Option<u64>,
+    /// Maximum wall-clock duration of a run, unlimited if `None`.
+    pub timeout: Option<Duration>,
+    /// Maximum size of a plugin instance memory, in 64KiB pages, unlimited if `None`.
+    pub max_memory_pages: Option<u32>,
+    /// Maximum number of queries a plugin can make itself during a run, unlimited if `None`.
+    pub max_sub_queries: Option<u32>,
+}
+
+impl ExecutionLimits {
+    /// Create the store for a plugin instance, enforcing the memory limit if any.
+    /// The returned flag is raised when the instance tries to go over the limit.
+    pub(crate) fn store(&self, engine: &Engine) -> (Store, Arc<AtomicBool>) {
+        let exceeded = Arc::new(AtomicBool::new(false));
+        let store = match self.max_memory_pages {
+            Some(pages) => {
+                // Tunables belong to each clone of the engine, its compiled code is shared.
+                let mut engine = engine.clone();
+                engine.set_tunables(LimitingTunables::new(Pages(pages), exceeded.clone()));
+                Store::new(engine)
+            }
+            None => Store::new(engine),
+        };
+        (store, exceeded)
+    }
+
+    /// Give a freshly created instance its full fuel budget.
+    pub(crate) fn refuel(&self, store: &mut Store, instance: &Instance) {
+        set_remaining_points(store, instance, self.fuel_budget().unwrap_or(u64::MAX));
+    }
+
+    /// The fuel a run can use: the fuel limit, or if there is only a timeout the fuel a plugin
+    /// would use in that time, so that a callback that never returns is stopped as well.
+    fn fuel_budget(&self) -> Option<u64> {
+        self.fuel.or_else(|| {
+            self.timeout.map(|timeout| {
+                let millis = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+                millis.saturating_mul(FUEL_PER_MILLISECOND)
+            })
+        })
+    }
+
+    /// The deadline of a run starting now, if any.
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Turn an instantiation error into a `PluginKilled` error if the plugin memory is too big.
+    /// The memory flag is cleared once reported.
+    pub(crate) fn check_instantiation(
+        &self,
+        memory_exceeded: &AtomicBool,
+        err: anyhow::Error,
+    ) -> QueryRunnerError {
+        match self.max_memory_pages {
+            Some(pages) if memory_exceeded.swap(false, Ordering::SeqCst) => {
+                PluginKilled::MemoryExceeded(pages).into()
+            }
+            _ => QueryRunnerError::Instantiation(format!("{err:#}")),
+        }
+    }
+
+    /// Turn a trap into a `PluginKilled` error if it was caused by the plugin
+    /// going over its memory limit or running out of fuel.
+    /// The memory flag is cleared once reported, so that it only explains the next trap.
+    pub(crate) fn check_trap(
+        &self,
+        store: &mut Store,
+        instance: &Instance,
+        memory_exceeded: &AtomicBool,
+        err: RuntimeError,
+    ) -> QueryRunnerError {
+        if let Some(pages) = self.max_memory_pages {
+            if memory_exceeded.swap(false, Ordering::SeqCst) {
+                return PluginKilled::MemoryExceeded(pages).into();
+            }
+        }
+        match (
+            get_remaining_points(store, instance),
+            self.fuel,
+            self.timeout,
+        ) {
+            (MeteringPoints::Exhausted, Some(fuel), _) => PluginKilled::FuelExhausted(fuel).into(),
+            (MeteringPoints::Exhausted, None, Some(timeout)) => {
+                PluginKilled::Timeout(timeout).into()
+            }
+            (MeteringPoints::Exhausted, None, None) => PluginKilled::FuelExhausted(u64::MAX).into(),
+            (MeteringPoints::Remaining(_), _, _) => err.into(),
+        }
+    }
+
+    /// Fail if the given deadline has passed.
+    pub(crate) fn check_deadline(&self, deadline: Option<Instant>) -> Result<()> {
+        match (deadline, self.timeout) {
+            (Some(deadline), Some(timeout)) if Instant::now() > deadline => {
+                Err(PluginKilled::Timeout(timeout).into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Error returned when a plugin run is stopped for exceeding its limits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginKilled {
+    /// The plugin used up its instruction budget.
+    FuelExhausted(u64),
+    /// The run took longer than allowed.
+    Timeout(Duration),
+    /// The plugin tried to use more memory pages than allowed.
+    MemoryExceeded(u32),
+}
+
+impl Display for PluginKilled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PluginKilled::FuelExhausted(fuel) => {
+                write!(f, "plugin ran out of fuel after {fuel} instructions")
+            }
+            PluginKilled::Timeout(timeout) => {
+                write!(f, "plugin run exceeded its timeout of {timeout:?}")
+            }
+            PluginKilled::MemoryExceeded(pages) => {
+                write!(f, "plugin exceeded memory limit of {pages} pages")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PluginKilled {}
+
+/// Tunables capping the memory of plugin instances.
+struct LimitingTunables {
+    /// Maximum number of pages.
+    limit: Pages,
+    /// Raised when an instance goes over the limit.
+    exceeded: Arc<AtomicBool>,
+    /// The tunables doing the actual work.
+    base: BaseTunables,
+}
+
+impl LimitingTunables {
+    fn new(limit: Pages, exceeded: Arc<AtomicBool>) -> Self {
+        LimitingTunables {
+            limit,
+            exceeded,
+            base: BaseTunables::for_target(&Target::default()),
+        }
+    }
+
+    /// Lower the declared maximum of a memory to the limit.
+    fn adjust_memory(&self, requested: &MemoryType) -> MemoryType {
+        let mut adjusted = *requested;
+        adjusted.maximum = Some(
+            requested
+                .maximum
+                .map_or(self.limit, |maximum| maximum.min(self.limit)),
+        );
+        adjusted
+    }
+
+    /// Refuse memories that start bigger than the limit.
+    fn validate_memory(&self, ty: &MemoryType) -> Result<(), MemoryError> {
+        if ty.minimum > self.limit {
+            self.exceeded.store(true, Ordering::SeqCst);
+            return Err(MemoryError::Generic(format!(
+                "memory minimum of {} pages exceeds the limit of {} pages",
+                ty.minimum.0, self.limit.0
+            )));
+        }
+        Ok(())
+    }
+
+    /// Wrap a memory to record failed growths.
+    fn monitor(&self, memory: VMMemory) -> VMMemory {
+        VMMemory(Box::new(MonitoredMemory {
+            memory: memory.0,
+            exceeded: self.exceeded.clone(),
+        }))
+    }
+}
+
+impl Tunables for LimitingTunables {
+    fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
+        self.base.memory_style(&self.adjust_memory(memory))
+    }
+
+    fn table_style(&self, table: &TableType) -> TableStyle {
+        self.base.table_style(table)
+    }
+
+    fn create_host_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+    ) -> Result<VMMemory, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base
+            .create_host_memory(&adjusted, style)
+            .map(|memory| self.monitor(memory))
+    }
+
+    unsafe fn create_vm_memory(
+        &self,
+        ty: &MemoryType,
+        style: &MemoryStyle,
+        vm_definition_location: NonNull<VMMemoryDefinition>,
+    ) -> Result<VMMemory, MemoryError> {
+        let adjusted = self.adjust_memory(ty);
+        self.validate_memory(&adjusted)?;
+        self.base
+            .create_vm_memory(&adjusted, style, vm_definition_location)
+            .map(|memory| self.monitor(memory))
+    }
+
+    fn create_host_table(&self, ty: &TableType, style: &TableStyle) -> Result<VMTable, String> {
+        self.base.create_host_table(ty, style)
+    }
+
+    unsafe fn create_vm_table(
+        &self,
+        ty: &TableType,
+        style: &TableStyle,
+        vm_definition_location: NonNull<VMTableDefinition>,
+    ) -> Result<VMTable, String> {
+        self.base.create_vm_table(ty, style, vm_definition_location)
+    }
+}
+
+/// A plugin memory recording whether it failed to grow past its maximum.
+#[derive(Debug)]
+struct MonitoredMemory {
+    memory: Box<dyn LinearMemory + 'static>,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl LinearMemory for MonitoredMemory {
+    fn ty(&self) -> MemoryType {
+        self.memory.ty()
+    }
+
+    fn size(&self) -> Pages {
+        self.memory.size()
+    }
+
+    fn style(&self) -> MemoryStyle {
+        self.memory.style()
+    }
+
+    fn grow(&mut self, delta: Pages) -> Result<Pages, MemoryError> {
+        let res = self.memory.grow(delta);
+        if let Err(MemoryError::CouldNotGrow { .. }) = res {
+            self.exceeded.store(true, Ordering::SeqCst);
+        }
+        res
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        self.memory.vmmemory()
+    }
+
+    fn try_clone(&self) -> Option<Box<dyn LinearMemory + 'static>> {
+        None
+    }
+
+    fn duplicate(&mut self) -> Result<Box<dyn LinearMemory + 'static>, MemoryError> {
+        Ok(Box::new(MonitoredMemory {
+            memory: self.memory.duplicate()?,
+            exceeded: self.exceeded.clone(),
+        }))
+    }
+}
+
+/// Fuel metering for all the modules an engine compiles.
+///
+/// A `Metering` middleware can only meter a single module, so a new one is created for each module.
+/// Each engine gets its own `ModuleMetering`, which its clones share along with the engine lock.
+/// Wasmer holds that lock for the whole compilation of a module, from the transformation of its
+/// info to the compilation of its last function, so the current metering is never replaced
+/// mid-compilation, even when several threads compile modules with the same engine.
+#[derive(Debug)]
+pub(crate) struct ModuleMetering {
+    /// Fuel cost of each operator.
+    cost: OperatorCost,
+    /// The metering of the module being compiled.
+    current: Mutex<Option<Metering<OperatorCost>>>,
+}
+
+/// Fuel cost of an operator.
+type OperatorCost = fn(&Operator) -> u64;
+
+impl ModuleMetering {
+    pub(crate) fn new(cost: OperatorCost) -> Self {
+        ModuleMetering {
+            cost,
+            current: Mutex::new(None),
+        }
+    }
+}
+
+impl ModuleMiddleware for ModuleMetering {
+    fn generate_function_middleware(
+        &self,
+        index: LocalFunctionIndex,
+    ) -> Box<dyn FunctionMiddleware> {
+        self.current
+            .lock()
+            .as_ref()
+            .expect("module info is transformed before functions")
+            .generate_function_middleware(index)
+    }
+
+    fn transform_module_info(&self, module_info: &mut ModuleInfo) {
+        let metering = Metering::new(u64::MAX, self.cost);
+        metering.transform_module_info(module_info);
+        *self.current.lock() = Some(metering);
+    }
+}
+
+/// Stop a result stream with a `PluginKilled::Timeout` error once the deadline has passed.
+///
+/// Results emitted past the deadline are dropped, but the error is only returned once the run
+/// has stopped and released its connection: it does at its next callback, or when it runs out of
+/// the fuel its timeout gives it.
+pub(crate) fn with_deadline(
+    stream: ResultStream<'_>,
+    deadline: Instant,
+    timeout: Duration,
+) -> ResultStream<'_> {
+    Box::pin(futures_util::stream::unfold(
+        Some(stream),
+        move |stream| async move {
+            let mut stream = stream?;
+            match tokio::time::timeout_at(deadline.into(), stream.next()).await {
+                Ok(Some(res)) => Some((res, Some(stream))),
+                Ok(None) => None,
+                Err(_) => {
+                    while stream.next().await.is_some() {}
+                    Some((Err(PluginKilled::Timeout(timeout).into()), None))
+                }
+            }
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{build_engine, build_engine_with, Compiler};
+    use anyhow::Result;
+    use wasmer::{imports, Module};
+
+    #[test]
+    fn test_fuel_exhausted() -> Result<()> {
+        // Metering is a middleware each compiler must apply.
+        for compiler in Compiler::ALL {
+            let engine = build_engine_with(*compiler);
+            let mut store = Store::new(&engine);
+            let module = Module::new(&store, r#"(module (func (export "spin") (loop br 0)))"#)?;
+            let instance = Instance::new(&mut store, &module, &imports! {})?;
+            let limits = ExecutionLimits {
+                fuel: Some(1000),
+                ..Default::default()
+            };
+            limits.refuel(&mut store, &instance);
+            let spin = instance.exports.get_function("spin")?;
+            let err = spin.call(&mut store, &[]).unwrap_err();
+            let err = limits.check_trap(&mut store, &instance, &AtomicBool::new(false), err);
+            assert!(matches!(
+                err,
+                QueryRunnerError::Killed(PluginKilled::FuelExhausted(1000))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fuel_concurrent_compilation() -> Result<()> {
+        // Modules with different numbers of globals put the metering globals at different indexes.
+        let engine = build_engine();
+        let threads: Vec<_> = (0..8)
+            .map(|globals| {
+                let engine = engine.clone();
+                std::thread::spawn(move || {
+                    let globals = "(global i32 (i32.const 0))".repeat(globals);
+                    let wat = format!(r#"(module {globals} (func (export "spin") (loop br 0)))"#);
+                    Module::new(&Store::new(engine), wat)
+                })
+            })
+            .collect();
+        for thread in threads {
+            let module = thread.join().unwrap()?;
+            let mut store = Store::new(&engine);
+            let instance = Instance::new(&mut store, &module, &imports! {})?;
+            let limits = ExecutionLimits {
+                fuel: Some(1000),
+                ..Default::default()
+            };
+            limits.refuel(&mut store, &instance);
+            let spin = instance.exports.get_function("spin")?;
+            let err = spin.call(&mut store, &[]).unwrap_err();
+            let err = limits.check_trap(&mut store, &instance, &AtomicBool::new(false), err);
+            assert!(matches!(
+                err,
+                QueryRunnerError::Killed(PluginKilled::FuelExhausted(1000))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_exceeded() -> Result<()> {
+        let engine = build_engine();
+        let limits = ExecutionLimits {
+            max_memory_pages: Some(2),
+            ..Default::default()
+        };
+        let (mut store, exceeded) = limits.store(&engine);
+        let module = Module::new(
+            &store,
+            r#"(module
+                (memory 1)
+                (func (export "grow") (result i32) (memory.grow (i32.const 2)))
+                (func (export "fail") unreachable))"#,
+        )?;
+        let instance = Instance::new(&mut store, &module, &imports! {})?;
+        let grow = instance.exports.get_function("grow")?;
+        // Growing past the limit fails inside WASM.
+        let res = grow.call(&mut store, &[])?;
+        assert_eq!(Some(-1), res[0].i32());
+        assert!(exceeded.load(Ordering::SeqCst));
+        // The next trap is blamed on the memory limit, the ones after it are not.
+        let fail = instance.exports.get_function("fail")?;
+        for killed in [true, false] {
+            let err = fail.call(&mut store, &[]).unwrap_err();
+            let err = limits.check_trap(&mut store, &instance, &exceeded, err);
+            assert_eq!(
+                killed,
+                matches!(
+                    err,
+                    QueryRunnerError::Killed(PluginKilled::MemoryExceeded(2))
+                )
+            );
+        }
+
+        let module = Module::new(&store, r#"(module (memory 3))"#)?;
+        let (mut store, exceeded) = limits.store(&engine);
+        let err = Instance::new(&mut store, &module, &imports! {})
+            .expect_err("instantiation should fail");
+        let err = limits.check_instantiation(&exceeded, err.into());
+        assert!(matches!(
+            err,
+            QueryRunnerError::Killed(PluginKilled::MemoryExceeded(2))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_timeout_fuel() -> Result<()> {
+        let engine = build_engine();
+        let mut store = Store::new(&engine);
+        let module = Module::new(&store, r#"(module (func (export "spin") (loop br 0)))"#)?;
+        let instance = Instance::new(&mut store, &module, &imports! {})?;
+        let timeout = Duration::from_millis(10);
+        let limits = ExecutionLimits {
+            timeout: Some(timeout),
+            ..Default::default()
+        };
+        limits.refuel(&mut store, &instance);
+        let spin = instance.exports.get_function("spin")?;
+        let err = spin.call(&mut store, &[]).unwrap_err();
+        let err = limits.check_trap(&mut store, &instance, &AtomicBool::new(false), err);
+        assert!(matches!(err, QueryRunnerError::Killed(PluginKilled::Timeout(t)) if t == timeout));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let timeout = Duration::from_millis(10);
+        let start = Instant::now();
+        // A run that only stops well after its deadline.
+        let run = futures_util::stream::once(async move {
+            tokio::time::sleep(timeout * 5).await;
+            Err(QueryRunnerError::Database("stopped".into()))
+        });
+        let mut stream = with_deadline(Box::pin(run), start + timeout, timeout);
+        let err = stream.next().await.unwrap().unwrap_err();
+        assert!(matches!(err, QueryRunnerError::Killed(PluginKilled::Timeout(t)) if t == timeout));
+        assert!(start.elapsed() >= timeout * 5);
+        assert!(stream.next().await.is_none());
+    }
+}
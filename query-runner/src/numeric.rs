@@ -0,0 +1,376 @@
+//! Exact numeric utilities.
+
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt::Write;
+
+use tokio_postgres::types::{private::BytesMut, to_sql_checked, FromSql, IsNull, ToSql, Type};
+
+use crate::error::{QueryRunnerError, Result};
+
+/// Sign of a positive Postgres NUMERIC.
+const NUMERIC_POS: u16 = 0x0000;
+/// Sign of a negative Postgres NUMERIC.
+const NUMERIC_NEG: u16 = 0x4000;
+/// Sign of a Postgres NUMERIC that is not a number.
+const NUMERIC_NAN: u16 = 0xC000;
+/// Sign of a Postgres NUMERIC positive infinity.
+const NUMERIC_PINF: u16 = 0xD000;
+/// Sign of a Postgres NUMERIC negative infinity.
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Postgres NUMERIC digits are in base 10000.
+const NBASE: i16 = 10_000;
+
+/// A parsed decimal number, borrowing its digits from the text representation.
+enum Decimal<'a> {
+    Finite {
+        negative: bool,
+        integer: &'a str,
+        fraction: &'a str,
+    },
+    NaN,
+    Infinity {
+        negative: bool,
+    },
+}
+
+impl<'a> Decimal<'a> {
+    /// Parse a plain decimal number like `-123.4500`, `NaN` or `Infinity`.
+    fn parse(value: &'a str) -> Result<Self> {
+        let (negative, unsigned) = match value.strip_prefix('-') {
+            Some(unsigned) => (true, unsigned),
+            None => (false, value.strip_prefix('+').unwrap_or(value)),
+        };
+        match unsigned {
+            "NaN" if unsigned.len() == value.len() => return Ok(Decimal::NaN),
+            "Infinity" => return Ok(Decimal::Infinity { negative }),
+            _ => {}
+        }
+        let (integer, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        if (integer.is_empty() && fraction.is_empty())
+            || !integer
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(QueryRunnerError::Parameter(format!(
+                "`{value}` is not a decimal number"
+            )));
+        }
+        Ok(Decimal::Finite {
+            negative,
+            integer,
+            fraction,
+        })
+    }
+
+    /// Rank in the order of Postgres: negative infinity, finite numbers, positive infinity, then NaN.
+    fn rank(&self) -> u8 {
+        match self {
+            Decimal::Infinity { negative: true } => 0,
+            Decimal::Finite { .. } => 1,
+            Decimal::Infinity { negative: false } => 2,
+            Decimal::NaN => 3,
+        }
+    }
+}
+
+/// Compare two decimal numbers exactly, in the order of Postgres where NaN is above all numbers.
+pub(crate) fn compare_numeric(a: &str, b: &str) -> Result<Ordering> {
+    let (a, b) = (Decimal::parse(a)?, Decimal::parse(b)?);
+    match (&a, &b) {
+        (
+            Decimal::Finite {
+                negative: a_negative,
+                integer: a_integer,
+                fraction: a_fraction,
+            },
+            Decimal::Finite {
+                negative: b_negative,
+                integer: b_integer,
+                fraction: b_fraction,
+            },
+        ) => {
+            // Without leading and trailing zeros, longer integer parts are larger,
+            // and same length digits compare as text.
+            let (a_integer, a_fraction) = (
+                a_integer.trim_start_matches('0'),
+                a_fraction.trim_end_matches('0'),
+            );
+            let (b_integer, b_fraction) = (
+                b_integer.trim_start_matches('0'),
+                b_fraction.trim_end_matches('0'),
+            );
+            // Zero has no sign.
+            let a_negative = *a_negative && !(a_integer.is_empty() && a_fraction.is_empty());
+            let b_negative = *b_negative && !(b_integer.is_empty() && b_fraction.is_empty());
+            let magnitude = a_integer
+                .len()
+                .cmp(&b_integer.len())
+                .then_with(|| a_integer.cmp(b_integer))
+                .then_with(|| a_fraction.cmp(b_fraction));
+            Ok(match (a_negative, b_negative) {
+                (false, false) => magnitude,
+                (true, true) => magnitude.reverse(),
+                (false, true) => Ordering::Greater,
+                (true, false) => Ordering::Less,
+            })
+        }
+        _ => Ok(a.rank().cmp(&b.rank())),
+    }
+}
+
+/// Check that a string is a valid decimal number.
+pub(crate) fn validate_numeric(value: &str) -> Result<&str> {
+    Decimal::parse(value).map(|_| value)
+}
+
+/// An exact decimal number, as its text representation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Numeric(pub String);
+
+impl<'a> FromSql<'a> for Numeric {
+    fn from_sql(_ty: &Type, raw: &'a [u8]) -> Result<Self, Box<dyn Error + Sync + Send>> {
+        let mut words = raw.chunks(2).map(|w| match w {
+            [hi, lo] => Ok(u16::from_be_bytes([*hi, *lo])),
+            _ => Err("invalid NUMERIC buffer size"),
+        });
+        let mut next = || words.next().unwrap_or(Err("invalid NUMERIC buffer size"));
+        let ndigits = next()? as i16;
+        let weight = next()? as i16;
+        let sign = next()?;
+        let dscale = next()? as usize;
+        let digits = (0..ndigits)
+            .map(|_| match next()? as i16 {
+                d @ 0..=9999 => Ok(d),
+                _ => Err("invalid NUMERIC digit"),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut out = String::new();
+        match sign {
+            NUMERIC_POS => {}
+            NUMERIC_NEG => out.push('-'),
+            NUMERIC_NAN => return Ok(Numeric("NaN".into())),
+            NUMERIC_PINF => return Ok(Numeric("Infinity".into())),
+            NUMERIC_NINF => return Ok(Numeric("-Infinity".into())),
+            _ => return Err("invalid NUMERIC sign".into()),
+        }
+        // Digit at the given position, positions before or after the stored digits being zeros.
+        let digit = |ix: i32| {
+            usize::try_from(ix)
+                .ok()
+                .and_then(|ix| digits.get(ix))
+                .copied()
+                .unwrap_or_default()
+        };
+        let weight = i32::from(weight);
+        if weight < 0 {
+            out.push('0');
+        } else {
+            write!(out, "{}", digit(0))?;
+            for ix in 1..=weight {
+                write!(out, "{:04}", digit(ix))?;
+            }
+        }
+        if dscale > 0 {
+            let mut fraction = String::with_capacity(dscale + 4);
+            let mut ix = weight + 1;
+            while fraction.len() < dscale {
+                write!(fraction, "{:04}", digit(ix))?;
+                ix += 1;
+            }
+            fraction.truncate(dscale);
+            out.push('.');
+            out.push_str(&fraction);
+        }
+        Ok(Numeric(out))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+}
+
+impl ToSql for Numeric {
+    fn to_sql(
+        &self,
+        _ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        let (negative, integer, fraction) = match Decimal::parse(&self.0)? {
+            Decimal::Finite {
+                negative,
+                integer,
+                fraction,
+            } => (negative, integer, fraction),
+            Decimal::NaN => return write_header(out, 0, 0, NUMERIC_NAN, 0),
+            Decimal::Infinity { negative: false } => {
+                return write_header(out, 0, 0, NUMERIC_PINF, 0)
+            }
+            Decimal::Infinity { negative: true } => {
+                return write_header(out, 0, 0, NUMERIC_NINF, 0)
+            }
+        };
+        let dscale = u16::try_from(fraction.len()).map_err(|_| "NUMERIC scale out of range")?;
+        let integer = integer.trim_start_matches('0');
+        // Align the digits on base 10000 boundaries around the decimal point.
+        let integer_pad = (4 - integer.len() % 4) % 4;
+        let fraction_pad = (4 - fraction.len() % 4) % 4;
+        let aligned: Vec<u8> = std::iter::repeat(b'0')
+            .take(integer_pad)
+            .chain(integer.bytes())
+            .chain(fraction.bytes())
+            .chain(std::iter::repeat(b'0').take(fraction_pad))
+            .collect();
+        let mut digits: Vec<i16> = aligned
+            .chunks(4)
+            .map(|chunk| {
+                chunk
+                    .iter()
+                    .fold(0, |acc, b| acc * 10 + i16::from(b - b'0'))
+            })
+            .collect();
+        let mut weight = ((integer_pad + integer.len()) / 4) as i32 - 1;
+        // Leading and trailing zeros are implied.
+        let leading = digits.iter().take_while(|d| **d == 0).count();
+        digits.drain(..leading);
+        weight -= leading as i32;
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        let (weight, sign) = if digits.is_empty() {
+            (0, NUMERIC_POS)
+        } else if negative {
+            (weight, NUMERIC_NEG)
+        } else {
+            (weight, NUMERIC_POS)
+        };
+        let ndigits = i16::try_from(digits.len()).map_err(|_| "NUMERIC out of range")?;
+        let weight = i16::try_from(weight).map_err(|_| "NUMERIC out of range")?;
+        write_header(out, ndigits, weight, sign, dscale)?;
+        for digit in digits {
+            debug_assert!(digit < NBASE);
+            out.extend_from_slice(&digit.to_be_bytes());
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        *ty == Type::NUMERIC
+    }
+
+    to_sql_checked!();
+}
+
+/// Write the header of a Postgres NUMERIC.
+fn write_header(
+    out: &mut BytesMut,
+    ndigits: i16,
+    weight: i16,
+    sign: u16,
+    dscale: u16,
+) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+    out.extend_from_slice(&ndigits.to_be_bytes());
+    out.extend_from_slice(&weight.to_be_bytes());
+    out.extend_from_slice(&sign.to_be_bytes());
+    out.extend_from_slice(&dscale.to_be_bytes());
+    Ok(IsNull::No)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &str) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        Numeric(value.into())
+            .to_sql(&Type::NUMERIC, &mut out)
+            .unwrap();
+        out.to_vec()
+    }
+
+    fn decode(raw: &[u8]) -> String {
+        Numeric::from_sql(&Type::NUMERIC, raw).unwrap().0
+    }
+
+    fn words(words: &[u16]) -> Vec<u8> {
+        words.iter().flat_map(|w| w.to_be_bytes()).collect()
+    }
+
+    #[test]
+    fn test_validate_numeric() {
+        assert!(validate_numeric("123").is_ok());
+        assert!(validate_numeric("-123.4500").is_ok());
+        assert!(validate_numeric("+.5").is_ok());
+        assert!(validate_numeric("5.").is_ok());
+        assert!(validate_numeric("NaN").is_ok());
+        assert!(validate_numeric("-Infinity").is_ok());
+        assert!(validate_numeric("").is_err());
+        assert!(validate_numeric(".").is_err());
+        assert!(validate_numeric("-NaN").is_err());
+        assert!(validate_numeric("1e10").is_err());
+        assert!(validate_numeric("12.3.4").is_err());
+        assert!(validate_numeric("twelve").is_err());
+    }
+
+    #[test]
+    fn test_compare_numeric() {
+        let cmp = |a, b| compare_numeric(a, b).unwrap();
+        assert_eq!(Ordering::Equal, cmp("1.50", "001.5"));
+        assert_eq!(Ordering::Equal, cmp("-0.0", "0"));
+        assert_eq!(Ordering::Less, cmp("9.99", "10"));
+        assert_eq!(Ordering::Less, cmp("0.45", ".5"));
+        assert_eq!(Ordering::Greater, cmp("-9.99", "-10"));
+        assert_eq!(Ordering::Less, cmp("-0.1", "0"));
+        assert_eq!(
+            Ordering::Greater,
+            cmp(
+                "123456789012345678901234567890.1",
+                "123456789012345678901234567890"
+            )
+        );
+        assert_eq!(Ordering::Less, cmp("-Infinity", "-1"));
+        assert_eq!(Ordering::Greater, cmp("Infinity", "99999"));
+        assert_eq!(Ordering::Greater, cmp("NaN", "Infinity"));
+        assert_eq!(Ordering::Equal, cmp("NaN", "NaN"));
+        assert!(compare_numeric("1", "one").is_err());
+    }
+
+    #[test]
+    fn test_numeric_encode() {
+        assert_eq!(words(&[3, 1, 0, 3, 1, 2345, 6780]), encode("12345.678"));
+        assert_eq!(words(&[1, 0xFFFF, 0x4000, 4, 1]), encode("-0.0001"));
+        assert_eq!(words(&[1, 2, 0, 0, 1]), encode("100000000"));
+        assert_eq!(words(&[0, 0, 0, 2]), encode("-000.00"));
+        assert_eq!(words(&[0, 0, 0xC000, 0]), encode("NaN"));
+    }
+
+    #[test]
+    fn test_numeric_decode() {
+        assert_eq!("12345.678", decode(&words(&[3, 1, 0, 3, 1, 2345, 6780])));
+        assert_eq!("-0.0001", decode(&words(&[1, 0xFFFF, 0x4000, 4, 1])));
+        assert_eq!("100000000", decode(&words(&[1, 2, 0, 0, 1])));
+        assert_eq!("0.00", decode(&words(&[0, 0, 0, 2])));
+        assert_eq!("-Infinity", decode(&words(&[0, 0, 0xF000, 0])));
+        assert!(Numeric::from_sql(&Type::NUMERIC, &[0, 1, 0]).is_err());
+        assert!(Numeric::from_sql(&Type::NUMERIC, &words(&[1, 0, 0, 0, 10_000])).is_err());
+    }
+
+    #[test]
+    fn test_numeric_round_trip() {
+        for value in [
+            "0",
+            "1",
+            "-1",
+            "9999",
+            "10000",
+            "0.5",
+            "123.4500",
+            "-98765432109876543210.0123456789",
+        ] {
+            assert_eq!(value, decode(&encode(value)));
+        }
+    }
+}
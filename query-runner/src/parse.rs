@@ -1,24 +1,103 @@
 //! Parsing utilities.
 
-use std::{borrow::Borrow, collections::HashMap, hash::Hash};
+use std::{
+    borrow::Borrow, cmp::Ordering, collections::HashMap, fmt::Display, hash::Hash, str::FromStr,
+};
 
+use crate::error::{QueryRunnerError, Result};
+use crate::numeric::{compare_numeric, validate_numeric};
 use crate::query::*;
-use anyhow::{anyhow, Result};
+use crate::temporal::{parse_date, parse_time, parse_timestamp};
+use regex::Regex;
+use serde::de::IgnoredAny;
+use uuid::Uuid;
 
-/// Parse parameters given as strings.
-pub fn parse_parameter_values<'a, T>(
+/// Compiled patterns of parameters, by pattern.
+pub(crate) type Patterns = HashMap<String, Regex>;
+
+/// Check the constraints of parameters suit their types, and compile their patterns.
+pub(crate) fn compile_patterns(parameters: &[Parameter], patterns: &mut Patterns) -> Result<()> {
+    for param in parameters {
+        check_parameter(param)?;
+        if let Some(pattern) = &param.constraints.pattern {
+            if !patterns.contains_key(pattern) {
+                // Match the whole value.
+                let regex = Regex::new(&format!("^(?:{pattern})$")).map_err(|err| {
+                    QueryRunnerError::Metadata(format!(
+                        "invalid pattern for parameter `{}`: {err}",
+                        param.name
+                    ))
+                })?;
+                patterns.insert(pattern.clone(), regex);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check the constraints of a parameter suit its type: bounds for numbers, length and pattern for strings.
+fn check_parameter(param: &Parameter) -> Result<()> {
+    let constraints = &param.constraints;
+    let name = &param.name;
+    let typ = &param.parameter_type;
+    let refuse = |constraint: &str| {
+        Err(QueryRunnerError::Metadata(format!(
+            "parameter `{name}` of type {typ} cannot have a {constraint}"
+        )))
+    };
+    let bounds = constraints.min.iter().chain(&constraints.max);
+    match typ {
+        ParameterType::TypeInteger | ParameterType::TypeDecimal | ParameterType::TypeNumeric => {
+            for bound in bounds {
+                let valid = match typ {
+                    ParameterType::TypeInteger => bound.parse::<i64>().is_ok(),
+                    ParameterType::TypeDecimal => bound.parse::<f64>().is_ok(),
+                    _ => validate_numeric(bound).is_ok(),
+                };
+                if !valid {
+                    return Err(invalid_bound(param, bound));
+                }
+            }
+        }
+        _ if bounds.count() > 0 => return refuse("minimum or maximum"),
+        _ => {}
+    }
+    if *typ != ParameterType::TypeString {
+        if constraints.max_length.is_some() {
+            return refuse("maximum length");
+        }
+        if constraints.pattern.is_some() {
+            return refuse("pattern");
+        }
+    }
+    Ok(())
+}
+
+/// Parse parameters given as strings, checking their constraints with the patterns compiled
+/// when their plugin was loaded. Missing parameters get their default value if they have one,
+/// null if they are optional.
+pub(crate) fn parse_parameter_values<'a, T>(
     parameters: &'a [Parameter],
     param_values: &'a HashMap<T, T>,
+    patterns: &Patterns,
 ) -> Result<Vec<VariableParam<'a>>>
 where
     T: Borrow<str> + Eq + Hash,
 {
     let mut values = Vec::new();
     for param in parameters {
-        let value = param_values
-            .get(&param.name)
-            .ok_or(anyhow!("no value provided for parameter `{}`", param.name))?;
-        let value = parse_value(&param.parameter_type, value.borrow())?;
+        check_parameter(param)?;
+        let value = match (param_values.get(&param.name), &param.default_value) {
+            (Some(value), _) => parse_checked(param, value.borrow(), patterns)?,
+            (None, Some(default)) => parse_checked(param, default, patterns)?,
+            (None, None) if !param.required => null_value(&param.parameter_type),
+            (None, None) => {
+                return Err(QueryRunnerError::Parameter(format!(
+                    "no value provided for parameter `{}`",
+                    param.name
+                )))
+            }
+        };
         values.push(VariableParam {
             name: &param.name,
             value,
@@ -27,27 +106,170 @@ where
     Ok(values)
 }
 
+/// Parse the value of a parameter and check it satisfies its constraints.
+fn parse_checked<'a>(
+    param: &Parameter,
+    value: &'a str,
+    patterns: &Patterns,
+) -> Result<ValueParam<'a>> {
+    let parsed = parse_value(&param.parameter_type, value)?;
+    check_constraints(param, value, &parsed, patterns)?;
+    Ok(parsed)
+}
+
 fn parse_value<'a>(typ: &ParameterType, value: &'a str) -> Result<ValueParam<'a>> {
     match typ {
         ParameterType::TypeBoolean => Ok(ValueParam::DataBoolean(Some(
-            value.to_ascii_lowercase() == "true",
+            value.eq_ignore_ascii_case("true"),
         ))),
         ParameterType::TypeDecimal => value
             .parse()
             .map(Option::Some)
             .map(ValueParam::DataDecimal)
-            .map_err(Into::into),
+            .map_err(invalid),
         ParameterType::TypeInteger => value
             .parse()
             .map(Option::Some)
             .map(ValueParam::DataInteger)
-            .map_err(Into::into),
-        // TODO parse the timestamp here, as early as possible.
-        ParameterType::TypeTimestamp => Ok(ValueParam::DataTimestamp(Some(value))),
+            .map_err(invalid),
+        ParameterType::TypeTimestamp => parse_timestamp(value)
+            .map(Option::Some)
+            .map(ValueParam::DataTimestamp),
+        ParameterType::TypeDate => parse_date(value)
+            .map(Option::Some)
+            .map(ValueParam::DataDate),
+        ParameterType::TypeTime => parse_time(value)
+            .map(Option::Some)
+            .map(ValueParam::DataTime),
+        ParameterType::TypeNumeric => validate_numeric(value)
+            .map(Option::Some)
+            .map(ValueParam::DataNumeric),
+        ParameterType::TypeUuid => Uuid::parse_str(value)
+            .map(|_| Some(value))
+            .map(ValueParam::DataUuid)
+            .map_err(invalid),
+        ParameterType::TypeJson => serde_json::from_str::<IgnoredAny>(value)
+            .map(|_| Some(value))
+            .map(ValueParam::DataJson)
+            .map_err(invalid),
         ParameterType::TypeString => Ok(ValueParam::DataString(Some(value))),
     }
 }
 
+/// Check a value, parsed from the given string, satisfies the constraints of its parameter.
+fn check_constraints(
+    param: &Parameter,
+    raw: &str,
+    value: &ValueParam,
+    patterns: &Patterns,
+) -> Result<()> {
+    let constraints = &param.constraints;
+    let name = &param.name;
+    if !constraints.allowed_values.is_empty()
+        && !constraints.allowed_values.iter().any(|v| v == raw)
+    {
+        return Err(QueryRunnerError::Parameter(format!(
+            "value `{raw}` of parameter `{name}` is not one of {}",
+            constraints.allowed_values.join(", ")
+        )));
+    }
+    match value {
+        ValueParam::DataInteger(Some(i)) => check_bounds(param, i, |bound| {
+            Ok(i.partial_cmp(&parse_bound(param, bound)?))
+        }),
+        ValueParam::DataDecimal(Some(d)) => check_bounds(param, d, |bound| {
+            Ok(d.partial_cmp(&parse_bound(param, bound)?))
+        }),
+        ValueParam::DataNumeric(Some(n)) => check_bounds(param, n, |bound| {
+            compare_numeric(n, bound)
+                .map(Some)
+                .map_err(|_| invalid_bound(param, bound))
+        }),
+        ValueParam::DataString(Some(s)) => {
+            if let Some(max_length) = constraints.max_length {
+                if s.chars().count() > max_length as usize {
+                    return Err(QueryRunnerError::Parameter(format!(
+                        "value of parameter `{name}` is longer than {max_length} characters"
+                    )));
+                }
+            }
+            if let Some(pattern) = &constraints.pattern {
+                let regex = patterns.get(pattern).ok_or_else(|| {
+                    QueryRunnerError::Metadata(format!(
+                        "pattern of parameter `{name}` was not compiled when the plugin was loaded"
+                    ))
+                })?;
+                if !regex.is_match(s) {
+                    return Err(QueryRunnerError::Parameter(format!(
+                        "value `{s}` of parameter `{name}` does not match `{pattern}`"
+                    )));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check a number is within the bounds of its parameter, given how it compares to a bound.
+/// Numbers not comparable to a bound, like a NaN decimal, are within it.
+fn check_bounds(
+    param: &Parameter,
+    value: impl Display,
+    compare: impl Fn(&str) -> Result<Option<Ordering>>,
+) -> Result<()> {
+    let name = &param.name;
+    if let Some(min) = &param.constraints.min {
+        if compare(min)? == Some(Ordering::Less) {
+            return Err(QueryRunnerError::Parameter(format!(
+                "value {value} of parameter `{name}` is less than {min}"
+            )));
+        }
+    }
+    if let Some(max) = &param.constraints.max {
+        if compare(max)? == Some(Ordering::Greater) {
+            return Err(QueryRunnerError::Parameter(format!(
+                "value {value} of parameter `{name}` is more than {max}"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Parse a bound of a parameter.
+fn parse_bound<T: FromStr>(param: &Parameter, bound: &str) -> Result<T> {
+    bound.parse().map_err(|_| invalid_bound(param, bound))
+}
+
+/// The error for an invalid bound of a parameter, a bug of its plugin.
+fn invalid_bound(param: &Parameter, bound: &str) -> QueryRunnerError {
+    QueryRunnerError::Metadata(format!(
+        "invalid bound `{bound}` for parameter `{}`",
+        param.name
+    ))
+}
+
+/// The null value of the given type.
+fn null_value(typ: &ParameterType) -> ValueParam<'static> {
+    match typ {
+        ParameterType::TypeBoolean => ValueParam::DataBoolean(None),
+        ParameterType::TypeDecimal => ValueParam::DataDecimal(None),
+        ParameterType::TypeInteger => ValueParam::DataInteger(None),
+        ParameterType::TypeTimestamp => ValueParam::DataTimestamp(None),
+        ParameterType::TypeDate => ValueParam::DataDate(None),
+        ParameterType::TypeTime => ValueParam::DataTime(None),
+        ParameterType::TypeNumeric => ValueParam::DataNumeric(None),
+        ParameterType::TypeUuid => ValueParam::DataUuid(None),
+        ParameterType::TypeJson => ValueParam::DataJson(None),
+        ParameterType::TypeString => ValueParam::DataString(None),
+    }
+}
+
+/// A parameter error from the given parsing error.
+fn invalid(err: impl Display) -> QueryRunnerError {
+    QueryRunnerError::Parameter(err.to_string())
+}
+
 /// Replace {{param}} by positional index.
 pub(crate) fn positional(
     prefix: &str,
@@ -71,6 +293,193 @@ mod tests {
     use super::*;
     use anyhow::Result;
 
+    /// A required integer parameter.
+    fn integer_parameter(name: &str) -> Parameter {
+        Parameter {
+            name: name.to_string(),
+            parameter_type: ParameterType::TypeInteger,
+            required: true,
+            default_value: None,
+            description: String::new(),
+            constraints: ParameterConstraints::default(),
+            values_query: None,
+        }
+    }
+
+    /// Parse values after compiling the patterns of the parameters, like loading their plugin does.
+    fn parse<'a>(
+        parameters: &'a [Parameter],
+        values: &'a HashMap<&'a str, &'a str>,
+    ) -> crate::Result<Vec<VariableParam<'a>>> {
+        let mut patterns = Patterns::new();
+        compile_patterns(parameters, &mut patterns)?;
+        parse_parameter_values(parameters, values, &patterns)
+    }
+
+    #[test]
+    fn test_parse_parameter_values_errors() {
+        let parameters = vec![integer_parameter("count")];
+        let err = parse(&parameters, &HashMap::new()).unwrap_err();
+        assert!(matches!(err, QueryRunnerError::Parameter(_)));
+        assert_eq!("no value provided for parameter `count`", err.to_string());
+
+        let values = HashMap::from([("count", "many")]);
+        let err = parse(&parameters, &values).unwrap_err();
+        assert!(matches!(err, QueryRunnerError::Parameter(_)));
+    }
+
+    #[test]
+    fn test_parse_parameter_values_defaults() -> Result<()> {
+        let parameters = vec![
+            Parameter {
+                default_value: Some("10".to_string()),
+                ..integer_parameter("limit")
+            },
+            Parameter {
+                required: false,
+                ..integer_parameter("offset")
+            },
+        ];
+        let none = HashMap::<&str, &str>::new();
+        let values = parse(&parameters, &none)?;
+        assert!(matches!(values[0].value, ValueParam::DataInteger(Some(10))));
+        assert!(matches!(values[1].value, ValueParam::DataInteger(None)));
+
+        let given = HashMap::from([("limit", "20"), ("offset", "5")]);
+        let values = parse(&parameters, &given)?;
+        assert!(matches!(values[0].value, ValueParam::DataInteger(Some(20))));
+        assert!(matches!(values[1].value, ValueParam::DataInteger(Some(5))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_parameter_values_constraints() {
+        let parameters = vec![
+            Parameter {
+                constraints: ParameterConstraints {
+                    min: Some("1".to_string()),
+                    max: Some("100".to_string()),
+                    ..Default::default()
+                },
+                ..integer_parameter("limit")
+            },
+            Parameter {
+                parameter_type: ParameterType::TypeString,
+                constraints: ParameterConstraints {
+                    max_length: Some(5),
+                    pattern: Some("[a-z]+".to_string()),
+                    ..Default::default()
+                },
+                ..integer_parameter("code")
+            },
+            Parameter {
+                parameter_type: ParameterType::TypeString,
+                required: false,
+                constraints: ParameterConstraints {
+                    allowed_values: vec!["open".to_string(), "closed".to_string()],
+                    ..Default::default()
+                },
+                ..integer_parameter("status")
+            },
+        ];
+        let check = |limit: &str, code: &str, status: Option<&str>| {
+            let mut values = HashMap::from([("limit", limit), ("code", code)]);
+            if let Some(status) = status {
+                values.insert("status", status);
+            }
+            parse(&parameters, &values).map(|_| ())
+        };
+        assert!(check("1", "abc", Some("open")).is_ok());
+        assert!(check("100", "abcde", None).is_ok());
+
+        assert!(matches!(
+            check("0", "abc", None),
+            Err(QueryRunnerError::Parameter(_))
+        ));
+        assert!(check("101", "abc", None).is_err());
+        assert!(check("50", "abcdef", None).is_err());
+        assert!(check("50", "ab1", None).is_err());
+        // The pattern must match the whole value.
+        assert!(check("50", "1ab", None).is_err());
+        assert!(check("50", "abc", Some("pending")).is_err());
+    }
+
+    #[test]
+    fn test_parse_parameter_values_numeric_bounds() {
+        let parameters = vec![Parameter {
+            parameter_type: ParameterType::TypeNumeric,
+            constraints: ParameterConstraints {
+                min: Some("-0.5".to_string()),
+                max: Some("12345678901234567890.25".to_string()),
+                ..Default::default()
+            },
+            ..integer_parameter("amount")
+        }];
+        let check =
+            |amount: &str| parse(&parameters, &HashMap::from([("amount", amount)])).map(|_| ());
+        assert!(check("-0.50").is_ok());
+        assert!(check("12345678901234567890.2500").is_ok());
+        assert!(matches!(
+            check("-0.51"),
+            Err(QueryRunnerError::Parameter(_))
+        ));
+        // Beyond the precision of a float.
+        assert!(check("12345678901234567890.26").is_err());
+        assert!(check("Infinity").is_err());
+    }
+
+    #[test]
+    fn test_compile_patterns() {
+        let invalid = |constraints: ParameterConstraints, parameter_type: ParameterType| {
+            let parameters = vec![Parameter {
+                parameter_type,
+                constraints,
+                ..integer_parameter("param")
+            }];
+            let err = compile_patterns(&parameters, &mut Patterns::new()).unwrap_err();
+            assert!(matches!(err, QueryRunnerError::Metadata(_)), "{err}");
+        };
+        let min = ParameterConstraints {
+            min: Some("1".to_string()),
+            ..Default::default()
+        };
+        let pattern = |pattern: &str| ParameterConstraints {
+            pattern: Some(pattern.to_string()),
+            ..Default::default()
+        };
+        invalid(min.clone(), ParameterType::TypeString);
+        invalid(min, ParameterType::TypeDate);
+        invalid(
+            ParameterConstraints {
+                max: Some("1.5".to_string()),
+                ..Default::default()
+            },
+            ParameterType::TypeInteger,
+        );
+        invalid(
+            ParameterConstraints {
+                max_length: Some(3),
+                ..Default::default()
+            },
+            ParameterType::TypeInteger,
+        );
+        invalid(pattern("[0-9]+"), ParameterType::TypeUuid);
+        invalid(pattern("[a-z"), ParameterType::TypeString);
+
+        let parameters = vec![Parameter {
+            parameter_type: ParameterType::TypeString,
+            constraints: pattern("[a-z]+"),
+            ..integer_parameter("code")
+        }];
+        let mut patterns = Patterns::new();
+        assert!(compile_patterns(&parameters, &mut patterns).is_ok());
+        assert!(patterns.contains_key("[a-z]+"));
+        // A pattern not compiled when loading its plugin is a bug of the plugin, not of the value.
+        let values = HashMap::from([("code", "abc")]);
+        let err = parse_parameter_values(&parameters, &values, &Patterns::new()).unwrap_err();
+        assert!(matches!(err, QueryRunnerError::Metadata(_)));
+    }
+
     #[test]
     fn test_parse_value_bool() -> Result<()> {
         assert!(matches!(
@@ -141,6 +550,72 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_parse_value_numeric() -> Result<()> {
+        assert!(matches!(
+            parse_value(&ParameterType::TypeNumeric, "123.4500")?,
+            ValueParam::DataNumeric(Some("123.4500"))
+        ));
+        assert!(matches!(
+            parse_value(&ParameterType::TypeNumeric, "-0.1")?,
+            ValueParam::DataNumeric(Some("-0.1"))
+        ));
+
+        assert!(parse_value(&ParameterType::TypeNumeric, "something").is_err());
+        assert!(parse_value(&ParameterType::TypeNumeric, "1.2e3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_value_uuid_json() -> Result<()> {
+        assert!(matches!(
+            parse_value(
+                &ParameterType::TypeUuid,
+                "67e55044-10b1-426f-9247-bb680e5fe0c8"
+            )?,
+            ValueParam::DataUuid(Some("67e55044-10b1-426f-9247-bb680e5fe0c8"))
+        ));
+        assert!(matches!(
+            parse_value(&ParameterType::TypeJson, r#"{"a": [1, 2]}"#)?,
+            ValueParam::DataJson(Some(r#"{"a": [1, 2]}"#))
+        ));
+
+        assert!(parse_value(&ParameterType::TypeUuid, "something").is_err());
+        assert!(parse_value(&ParameterType::TypeJson, "{").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_value_temporal() -> Result<()> {
+        assert!(matches!(
+            parse_value(&ParameterType::TypeTimestamp, "2023-04-21T10:30:00Z")?,
+            ValueParam::DataTimestamp(Some(Timestamp {
+                micros: 1_682_073_000_000_000,
+                offset_seconds: Some(0)
+            }))
+        ));
+        assert!(matches!(
+            parse_value(&ParameterType::TypeTimestamp, "2023-04-21T10:30:00")?,
+            ValueParam::DataTimestamp(Some(Timestamp {
+                micros: 1_682_073_000_000_000,
+                offset_seconds: None
+            }))
+        ));
+        assert!(matches!(
+            parse_value(&ParameterType::TypeDate, "2023-04-21")?,
+            ValueParam::DataDate(Some(19_468))
+        ));
+        assert!(matches!(
+            parse_value(&ParameterType::TypeTime, "10:30:00")?,
+            ValueParam::DataTime(Some(37_800_000_000))
+        ));
+
+        assert!(parse_value(&ParameterType::TypeTimestamp, "something").is_err());
+        assert!(parse_value(&ParameterType::TypeDate, "21/04/2023").is_err());
+        assert!(parse_value(&ParameterType::TypeTime, "123").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_positional() {
         assert_eq!("hello", positional("$", 1, "hello", &[]));
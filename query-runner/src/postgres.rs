@@ -1,220 +1,1003 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::PathBuf;
 use std::pin::Pin;
-use std::task::Poll;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
-use futures_util::{Stream, StreamExt};
-use pin_project::pin_project;
+use bb8::PooledConnection;
+use bb8_postgres::PostgresConnectionManager;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use futures_util::TryStreamExt;
+use native_tls::{Certificate, TlsConnector};
+use postgres_native_tls::MakeTlsConnector;
+use serde::Deserialize;
 use serde_yaml::Value;
-use tokio_postgres::types::{ToSql, Type};
-use tokio_postgres::{Client, Config, NoTls, RowStream, Statement};
+use tokio::runtime::Handle;
+use tokio::sync::OnceCell;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::private::BytesMut;
+use tokio_postgres::types::{to_sql_checked, Format, FromSql, IsNull, Kind, ToSql, Type};
+use tokio_postgres::{Client, Column, Config, NoTls, Row, RowStream, Statement};
+use uuid::Uuid;
 
-use crate::VariableResult;
+use crate::blocking::{spawn_execution, Emit};
+use crate::error::{QueryRunnerError, Result};
+use crate::numeric::Numeric;
+use crate::temporal::{date_from_days, days_from_date, micros_from_time, time_from_micros};
 use crate::{
-    add_result, parse::positional, DBConnection, ExecutionState, QueryResult, ValueResult, Variable,
+    parse::positional, DBConnection, ExecutionState, QueryResult, ResultStream, ValueResult,
+    Variable,
 };
+use crate::{
+    ColumnResult, ColumnType, FieldResult, QueryMode, ScalarResult, Timestamp, VariableResult,
+};
+
+/// Number of the next cursor the rows of the query of a plugin are fetched from.
+static NEXT_CURSOR: AtomicU64 = AtomicU64::new(0);
+
+/// Number of rows fetched from the cursor at a time.
+const FETCH_ROWS: u32 = 1_000;
+
+/// Error type of the Postgres conversion traits.
+type BoxError = Box<dyn std::error::Error + Sync + Send>;
 
 /// Create a new connection from a configuration value.
-pub(crate) fn new_connection(value: Value) -> Result<DBConnection> {
-    let config = value
+pub(crate) fn new_connection(value: Value, read_only: bool) -> Result<DBConnection> {
+    let mut config: Config = value
         .get("config")
-        .ok_or(anyhow!("No config provided"))?
+        .ok_or(configuration("No config provided"))?
         .as_str()
-        .ok_or(anyhow!("config is not a string"))?;
+        .ok_or(configuration("config is not a string"))?
+        .parse()
+        .map_err(|err| configuration(format!("invalid config: {err}")))?;
+    let pool: PoolOptions = match value.get("pool") {
+        Some(pool) => serde_yaml::from_value(pool.clone())
+            .map_err(|err| configuration(format!("invalid pool: {err}")))?,
+        None => PoolOptions::default(),
+    };
+    let tls: TlsOptions = match value.get("tls") {
+        Some(tls) => serde_yaml::from_value(tls.clone())
+            .map_err(|err| configuration(format!("invalid tls: {err}")))?,
+        // Honour a `sslmode=require` given in the config string.
+        None if config.get_ssl_mode() == SslMode::Require => TlsOptions {
+            mode: TlsMode::Require,
+            ca_path: None,
+        },
+        None => TlsOptions::default(),
+    };
+    let tls = tls.connector()?;
+    if tls.is_some() {
+        config.ssl_mode(SslMode::Require);
+    }
 
-    Ok(DBConnection::PostgresConnection(Box::new(config.parse()?)))
+    Ok(DBConnection::PostgresConnection(Box::new(PostgresPool {
+        config,
+        tls,
+        options: pool,
+        read_only,
+        pool: OnceCell::new(),
+    })))
 }
 
-/// Execute a query and return the result.
-pub(crate) async fn execute(
-    config: &Config,
-    state: &mut ExecutionState,
-) -> Result<Option<QueryResult>> {
-    let mut result = Option::None;
-    let mut stream = execute_stream(config, state).await?;
-    while let Some(res) = stream.next().await {
-        result = add_result(result, Some(res?));
+/// A configuration error with the given message.
+fn configuration(message: impl Into<String>) -> QueryRunnerError {
+    QueryRunnerError::Configuration(message.into())
+}
+
+/// Pool settings, from the `pool` section of the connection.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolOptions {
+    /// Number of idle connections to maintain.
+    min_size: Option<u32>,
+    /// Maximum number of connections.
+    max_size: Option<u32>,
+    /// Idle time in seconds before a connection is closed.
+    idle_timeout: Option<u64>,
+}
+
+/// TLS settings, from the `tls` section of the connection.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsOptions {
+    #[serde(default)]
+    mode: TlsMode,
+    /// PEM file with additional root certificates.
+    ca_path: Option<PathBuf>,
+}
+
+/// TLS modes, with the same meaning as the libpq `sslmode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TlsMode {
+    #[default]
+    Disable,
+    /// Encrypt, but do not check the server certificate.
+    Require,
+    /// Encrypt and check the server certificate is signed by a trusted CA.
+    VerifyCa,
+    /// Encrypt and check the server certificate matches the host.
+    VerifyFull,
+}
+
+impl TlsOptions {
+    /// Build the TLS connector, if TLS is enabled.
+    fn connector(&self) -> Result<Option<MakeTlsConnector>> {
+        if self.mode == TlsMode::Disable {
+            return Ok(None);
+        }
+        let mut builder = TlsConnector::builder();
+        if let Some(ca_path) = &self.ca_path {
+            let pem = fs::read(ca_path).map_err(|err| {
+                configuration(format!("cannot read CA file {}: {err}", ca_path.display()))
+            })?;
+            let certificate = Certificate::from_pem(&pem).map_err(|err| {
+                configuration(format!("invalid CA file {}: {err}", ca_path.display()))
+            })?;
+            builder.add_root_certificate(certificate);
+        }
+        builder
+            .danger_accept_invalid_certs(self.mode == TlsMode::Require)
+            .danger_accept_invalid_hostnames(self.mode != TlsMode::VerifyFull);
+        let connector = builder
+            .build()
+            .map_err(|err| configuration(format!("cannot set up TLS: {err}")))?;
+        Ok(Some(MakeTlsConnector::new(connector)))
     }
-    Ok(result)
 }
 
-/// Execute a query and return the result.
-pub(crate) async fn execute_stream<'a>(
-    config: &Config,
-    state: &'a mut ExecutionState,
-) -> Result<Pin<Box<impl Stream<Item = Result<QueryResult>> + 'a>>> {
-    // Get the query SQL.
-    let query = state
-        .query
-        .execution_query_string(&mut state.store, &state.execution)?;
-    // Get parameters.
-    let params = state
-        .query
-        .execution_variables(&mut state.store, &state.execution)?;
-
-    let query = positional("$", 1, &query, &params);
-
-    let (client, connection) = config.connect(NoTls).await?;
-    tokio::spawn(async move {
-        if let Err(e) = connection.await {
-            eprintln!("connection error: {}", e);
-        }
-    });
-    let stmt = client.prepare(&query).await?;
-
-    let st = QueryResultStream::new(state, client, stmt, &params).await?;
-    Ok(Box::pin(st))
-}
-
-#[pin_project]
-struct QueryResultStream<'a> {
-    state: &'a mut ExecutionState,
-    client: Client,
-    stmt: Statement,
-    #[pin]
-    it: RowStream,
-    at_end: bool,
-}
-
-impl<'a> QueryResultStream<'a> {
-    async fn new(
-        state: &'a mut ExecutionState,
-        client: Client,
-        stmt: Statement,
-        params: &[VariableResult],
-    ) -> Result<QueryResultStream<'a>> {
-        let it = client.query_raw(&stmt, params).await?;
-        Ok(QueryResultStream {
-            state,
-            client,
-            stmt,
-            it,
-            at_end: false,
+/// A pool of Postgres connections, created on first use.
+pub struct PostgresPool {
+    config: Config,
+    tls: Option<MakeTlsConnector>,
+    options: PoolOptions,
+    /// Refuse write queries.
+    pub(crate) read_only: bool,
+    pool: OnceCell<Pool>,
+}
+
+/// Pools differ by their TLS connector.
+#[derive(Clone)]
+enum Pool {
+    Plain(bb8::Pool<PostgresConnectionManager<NoTls>>),
+    Tls(bb8::Pool<PostgresConnectionManager<MakeTlsConnector>>),
+}
+
+/// A client checked out of the pool, returned to it when dropped.
+enum PooledClient {
+    Plain(PooledConnection<'static, PostgresConnectionManager<NoTls>>),
+    Tls(PooledConnection<'static, PostgresConnectionManager<MakeTlsConnector>>),
+}
+
+impl Deref for PooledClient {
+    type Target = Client;
+
+    fn deref(&self) -> &Client {
+        match self {
+            PooledClient::Plain(client) => client,
+            PooledClient::Tls(client) => client,
+        }
+    }
+}
+
+impl Pool {
+    /// Get a client from the pool.
+    async fn get(&self) -> Result<PooledClient> {
+        Ok(match self {
+            Pool::Plain(pool) => PooledClient::Plain(pool.get_owned().await?),
+            Pool::Tls(pool) => PooledClient::Tls(pool.get_owned().await?),
         })
     }
 }
 
-impl<'a> Stream for QueryResultStream<'a> {
-    type Item = Result<QueryResult>;
-
-    fn poll_next(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-    ) -> std::task::Poll<Option<Self::Item>> {
-        let this = self.project();
-        if *this.at_end {
-            return Poll::Ready(None);
-        }
-        match this.it.poll_next(cx) {
-            Poll::Ready(orow) => {
-                match orow {
-                    Some(Ok(row)) => {
-                        // Build row.
-                        let columns = this.stmt.columns();
-                        let mut result_one = Vec::with_capacity(columns.len());
-                        for (ix, col) in columns.iter().enumerate() {
-                            match col.type_() {
-                                &Type::INT2 | &Type::INT4 | &Type::INT8 => {
-                                    result_one.push(Variable {
-                                        name: col.name(),
-                                        value: ValueResult::DataInteger(row.get(ix)),
-                                    })
-                                }
-                                &Type::TEXT => result_one.push(Variable {
-                                    name: col.name(),
-                                    value: ValueResult::DataString(row.get(ix)),
-                                }),
-                                &Type::BOOL => result_one.push(Variable {
-                                    name: col.name(),
-                                    value: ValueResult::DataBoolean(row.get(ix)),
-                                }),
-                                &Type::FLOAT4 | &Type::FLOAT8 => result_one.push(Variable {
-                                    name: col.name(),
-                                    value: ValueResult::DataDecimal(row.get(ix)),
-                                }),
-                                _ => {
-                                    return Poll::Ready(Some(Err(anyhow!(
-                                        "unsupported type {}",
-                                        col.type_()
-                                    ))))
-                                }
-                            }
-                        }
-                        // Send row to plugin.
-                        match this.state.row(result_one).transpose() {
-                            Some(res) => Poll::Ready(Some(res)),
-                            None => {
-                                cx.waker().wake_by_ref();
-                                Poll::Pending
-                            },
+impl PostgresPool {
+    /// Get the pool, creating it if needed.
+    async fn pool(&self) -> Result<&Pool> {
+        self.pool.get_or_try_init(|| self.build()).await
+    }
+
+    async fn build(&self) -> Result<Pool> {
+        Ok(match &self.tls {
+            None => Pool::Plain(
+                self.builder()
+                    .build(PostgresConnectionManager::new(self.config.clone(), NoTls))
+                    .await?,
+            ),
+            Some(tls) => Pool::Tls(
+                self.builder()
+                    .build(PostgresConnectionManager::new(
+                        self.config.clone(),
+                        tls.clone(),
+                    ))
+                    .await?,
+            ),
+        })
+    }
+
+    /// Pool builder with the configured options, for either kind of connection.
+    fn builder<M: bb8::ManageConnection>(&self) -> bb8::Builder<M> {
+        // Keep the bb8 defaults for anything not configured.
+        let mut builder = bb8::Pool::builder().min_idle(self.options.min_size);
+        if let Some(max_size) = self.options.max_size {
+            builder = builder.max_size(max_size);
+        }
+        if let Some(idle_timeout) = self.options.idle_timeout {
+            builder = builder.idle_timeout(Some(Duration::from_secs(idle_timeout)));
+        }
+        builder
+    }
+}
+
+/// Execute a query on a blocking thread and stream the results back.
+pub(crate) async fn execute_stream(
+    pool: &PostgresPool,
+    mut state: ExecutionState,
+) -> Result<ResultStream<'static>> {
+    let read_only = pool.read_only;
+    let client = Arc::new(pool.pool().await?.get().await?);
+    let handle = Handle::current();
+
+    Ok(spawn_execution(move |emit| {
+        // Sub-queries run on the client of the run, in its transaction.
+        let sub_client = client.clone();
+        let sub_handle = handle.clone();
+        state.set_sub_query_runner(Some(Box::new(
+            move |query: &str, params: &[VariableResult]| {
+                sub_handle.block_on(savepoint_sub_query(&sub_client, query, params))
+            },
+        )));
+        let res = execute(&handle, &client, read_only, &mut state, emit);
+        state.set_sub_query_runner(None);
+        res
+    }))
+}
+
+/// Execute the queries of a plugin, sending each intermediate result to the given callback.
+/// The queries run in a transaction, only committed if they all succeed, read-only on a
+/// read-only connection.
+fn execute(
+    handle: &Handle,
+    client: &Client,
+    read_only: bool,
+    state: &mut ExecutionState,
+    emit: Emit<'_>,
+) -> Result<()> {
+    let begin = if read_only {
+        "BEGIN READ ONLY"
+    } else {
+        "BEGIN"
+    };
+    transaction(handle, client, begin, || {
+        execute_queries(handle, client, state, emit)
+    })
+}
+
+/// Call the given function in a transaction started with the given statement,
+/// committing it only if the function succeeds.
+fn transaction<T>(
+    handle: &Handle,
+    client: &Client,
+    begin: &str,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    handle.block_on(client.batch_execute(begin))?;
+    let mut rollback = Rollback {
+        handle,
+        client,
+        armed: true,
+    };
+    let res = f()?;
+    rollback.armed = false;
+    handle.block_on(client.batch_execute("COMMIT"))?;
+    Ok(res)
+}
+
+/// Rolls back the transaction of a run when dropped armed, as it is if the run fails or panics,
+/// so that its client goes back to the pool outside of any transaction.
+struct Rollback<'a> {
+    handle: &'a Handle,
+    client: &'a Client,
+    armed: bool,
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            // Report the error of the queries rather than the one of the rollback, if any.
+            let _ = self.handle.block_on(self.client.batch_execute("ROLLBACK"));
+        }
+    }
+}
+
+/// Run a query of the host itself, returning all its rows.
+pub(crate) async fn query(pool: &PostgresPool, query: &str) -> Result<QueryResult> {
+    let client = pool.pool().await?.get().await?;
+    if !pool.read_only {
+        return sub_query(&client, query, &[]).await;
+    }
+    client.batch_execute("BEGIN READ ONLY").await?;
+    let res = sub_query(&client, query, &[]).await;
+    match res {
+        Ok(_) => client.batch_execute("COMMIT").await?,
+        // Report the error of the query rather than the one of the rollback, if any.
+        Err(_) => {
+            let _ = client.batch_execute("ROLLBACK").await;
+        }
+    }
+    res
+}
+
+/// Execute the query of a plugin and the ones it chains.
+fn execute_queries(
+    handle: &Handle,
+    client: &Client,
+    state: &mut ExecutionState,
+    emit: Emit<'_>,
+) -> Result<()> {
+    // Get the query SQL.
+    let mut query = state.query_string()?;
+    // Get parameters.
+    let mut params = state.variables()?;
+
+    loop {
+        let stmt = handle.block_on(prepare(client, &query, &params))?;
+        if state.mode == QueryMode::Write {
+            let count = handle.block_on(client.execute_raw(&stmt, &params))?;
+            // Send affected row count to plugin.
+            if let Some(qr) = state.affected(count)? {
+                emit(qr)?;
+            }
+        } else {
+            state.columns(&columns(stmt.columns()))?;
+            // Fetch the rows from a cursor: the client is free for sub-queries between fetches,
+            // when it would be busy until the last row is read with a single query.
+            match handle.block_on(declare_cursor(client, &query, &params))? {
+                Some(cursor) => {
+                    let fetch = format!("FETCH {FETCH_ROWS} FROM {cursor}");
+                    loop {
+                        let rows = handle.block_on(client.query(&fetch, &[]))?;
+                        if rows.is_empty() {
+                            break;
                         }
+                        send_rows(state, &rows, stmt.columns(), emit)?;
                     }
-                    Some(Err(err)) => Poll::Ready(Some(Err(anyhow!(err)))),
-                    None => {
-                        *this.at_end = true;
-                        let columns = this.stmt.columns();
-                        let names: Vec<&str> = columns.iter().map(|c| c.name()).collect();
-                        Poll::Ready(
-                            this.state
-                                .query
-                                .execution_end(&mut this.state.store, &this.state.execution, &names)
-                                .map_err(|err| anyhow!(err))
-                                .transpose(),
-                        )
-                    }
+                    handle.block_on(client.batch_execute(&format!("CLOSE {cursor}")))?;
+                }
+                None => {
+                    // Other statements returning rows, such as `INSERT ... RETURNING`, `SHOW`
+                    // or `EXPLAIN`, are read whole before the plugin gets their rows.
+                    let rows: Vec<Row> = handle.block_on(async {
+                        client.query_raw(&stmt, &params).await?.try_collect().await
+                    })?;
+                    send_rows(state, &rows, stmt.columns(), emit)?;
                 }
             }
-            Poll::Pending => Poll::Pending,
+            // Send the last batch, if any.
+            if let Some(qr) = state.flush_rows()? {
+                emit(qr)?;
+            }
+        }
+        if let Some(qr) = state.end(&columns(stmt.columns()))? {
+            emit(qr)?;
+        }
+        match state.next()? {
+            Some(next) => (query, params) = (next.query_string, next.variables),
+            None => return Ok(()),
         }
     }
 }
 
+/// Send rows to the plugin.
+fn send_rows(
+    state: &mut ExecutionState,
+    rows: &[Row],
+    columns: &[Column],
+    emit: Emit<'_>,
+) -> Result<()> {
+    for row in rows {
+        let variables = row_variables(row, columns)?;
+        // Send row to plugin.
+        if let Some(qr) = state.row(variables)? {
+            emit(qr)?;
+        }
+    }
+    Ok(())
+}
+
+/// Declare a cursor for a query, returning its name, unless Postgres cannot declare one for it:
+/// only SELECT and VALUES statements without data-modifying WITH queries can be read from a
+/// cursor. The attempt runs in a savepoint, so that its failure does not abort the transaction.
+async fn declare_cursor(
+    client: &Client,
+    query: &str,
+    params: &[VariableResult],
+) -> Result<Option<String>> {
+    let cursor = format!(
+        "plugin_rows_{}",
+        NEXT_CURSOR.fetch_add(1, Ordering::Relaxed)
+    );
+    let declare = format!("DECLARE {cursor} NO SCROLL CURSOR FOR {query}");
+    client.batch_execute("SAVEPOINT declare_cursor").await?;
+    let declared = match prepare(client, &declare, params).await {
+        Ok(stmt) => client.execute_raw(&stmt, params).await.map_err(Into::into),
+        Err(err) => Err(err),
+    };
+    match declared {
+        Ok(_) => {
+            client
+                .batch_execute("RELEASE SAVEPOINT declare_cursor")
+                .await?;
+            Ok(Some(cursor))
+        }
+        // The statement itself fails again when run, if it is the cause of the error.
+        Err(_) => {
+            client
+                .batch_execute(
+                    "ROLLBACK TO SAVEPOINT declare_cursor; RELEASE SAVEPOINT declare_cursor",
+                )
+                .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Run a query made by the plugin itself in a savepoint, so that its failure does not abort the
+/// transaction of the run.
+async fn savepoint_sub_query(
+    client: &Client,
+    query: &str,
+    params: &[VariableResult],
+) -> Result<QueryResult> {
+    client.batch_execute("SAVEPOINT sub_query").await?;
+    match sub_query(client, query, params).await {
+        Ok(res) => {
+            client.batch_execute("RELEASE SAVEPOINT sub_query").await?;
+            Ok(res)
+        }
+        Err(err) => {
+            // Report the error of the query rather than the one of the rollback, if any.
+            let _ = client
+                .batch_execute("ROLLBACK TO SAVEPOINT sub_query")
+                .await;
+            Err(err)
+        }
+    }
+}
+
+/// Run a query made by the plugin itself, returning all its rows.
+async fn sub_query(client: &Client, query: &str, params: &[VariableResult]) -> Result<QueryResult> {
+    let (stmt, rows) = query_rows(client, query, params).await?;
+    let rows: Vec<Row> = rows.try_collect().await?;
+    let mut values = Vec::with_capacity(rows.len());
+    for row in rows.iter() {
+        let variables = row_variables(row, stmt.columns())?;
+        values.push(variables.into_iter().map(|v| v.value).collect());
+    }
+    Ok(QueryResult {
+        columns: columns(stmt.columns()),
+        values,
+    })
+}
+
+/// Describe the columns of a statement.
+fn columns(columns: &[Column]) -> Vec<ColumnResult> {
+    columns
+        .iter()
+        .map(|c| ColumnResult {
+            name: c.name().to_string(),
+            column_type: column_type(c.type_()),
+            // Postgres does not tell for query results.
+            nullable: None,
+            db_type: Some(c.type_().name().to_string()),
+        })
+        .collect()
+}
+
+/// Logical type of the values read from a column of the given type, as in `row_variables`.
+fn column_type(ty: &Type) -> ColumnType {
+    match ty {
+        &Type::INT2 | &Type::INT4 | &Type::INT8 => ColumnType::TypeInteger,
+        &Type::TEXT => ColumnType::TypeString,
+        &Type::BOOL => ColumnType::TypeBoolean,
+        &Type::FLOAT4 | &Type::FLOAT8 => ColumnType::TypeDecimal,
+        &Type::NUMERIC => ColumnType::TypeNumeric,
+        &Type::BYTEA => ColumnType::TypeBytes,
+        &Type::UUID => ColumnType::TypeUuid,
+        &Type::JSON | &Type::JSONB => ColumnType::TypeJson,
+        &Type::TIMESTAMP | &Type::TIMESTAMPTZ => ColumnType::TypeTimestamp,
+        &Type::DATE => ColumnType::TypeDate,
+        &Type::TIME => ColumnType::TypeTime,
+        ty if <Vec<Scalar> as FromSql>::accepts(ty) => ColumnType::TypeList,
+        ty if Composite::accepts(ty) => ColumnType::TypeRecord,
+        // Reading its values fails.
+        _ => ColumnType::TypeUnknown,
+    }
+}
+
+/// Prepare a query, with positional parameters.
+async fn prepare(client: &Client, query: &str, params: &[VariableResult]) -> Result<Statement> {
+    let query = positional("$", 1, query, params);
+    Ok(client.prepare(&query).await?)
+}
+
+/// Prepare and run a query.
+async fn query_rows(
+    client: &Client,
+    query: &str,
+    params: &[VariableResult],
+) -> Result<(Statement, Pin<Box<RowStream>>)> {
+    let stmt = prepare(client, query, params).await?;
+    let rows = client.query_raw(&stmt, params).await?;
+    Ok((stmt, Box::pin(rows)))
+}
+
+/// Read a row into variables named after the columns.
+fn row_variables<'a>(row: &Row, columns: &'a [Column]) -> Result<Vec<Variable<'a>>> {
+    let mut variables = Vec::with_capacity(columns.len());
+    for (ix, col) in columns.iter().enumerate() {
+        let value = match col.type_() {
+            &Type::INT2 | &Type::INT4 | &Type::INT8 => ValueResult::DataInteger(row.get(ix)),
+            &Type::TEXT => ValueResult::DataString(row.get(ix)),
+            &Type::BOOL => ValueResult::DataBoolean(row.get(ix)),
+            &Type::FLOAT4 | &Type::FLOAT8 => ValueResult::DataDecimal(row.get(ix)),
+            &Type::NUMERIC => {
+                ValueResult::DataNumeric(row.get::<_, Option<Numeric>>(ix).map(|n| n.0))
+            }
+            &Type::BYTEA => ValueResult::DataBytes(row.get(ix)),
+            &Type::UUID => {
+                ValueResult::DataUuid(row.get::<_, Option<Uuid>>(ix).map(|u| u.to_string()))
+            }
+            &Type::JSON | &Type::JSONB => ValueResult::DataJson(
+                row.get::<_, Option<serde_json::Value>>(ix)
+                    .map(|j| j.to_string()),
+            ),
+            &Type::TIMESTAMP => ValueResult::DataTimestamp(
+                row.get::<_, Option<NaiveDateTime>>(ix).map(Timestamp::from),
+            ),
+            &Type::TIMESTAMPTZ => ValueResult::DataTimestamp(
+                row.get::<_, Option<DateTime<Utc>>>(ix).map(Timestamp::from),
+            ),
+            &Type::DATE => {
+                ValueResult::DataDate(row.get::<_, Option<NaiveDate>>(ix).map(days_from_date))
+            }
+            &Type::TIME => {
+                ValueResult::DataTime(row.get::<_, Option<NaiveTime>>(ix).map(micros_from_time))
+            }
+            ty if <Vec<Scalar> as FromSql>::accepts(ty) => ValueResult::DataList(
+                row.get::<_, Option<Vec<Scalar>>>(ix)
+                    .map(|l| l.into_iter().map(|s| s.0).collect()),
+            ),
+            ty if Composite::accepts(ty) => {
+                ValueResult::DataRecord(row.get::<_, Option<Composite>>(ix).map(|c| c.0))
+            }
+            ty => {
+                return Err(QueryRunnerError::Database(
+                    format!("unsupported type {ty}").into(),
+                ))
+            }
+        };
+        variables.push(Variable {
+            name: col.name(),
+            value,
+        });
+    }
+    Ok(variables)
+}
+
+impl VariableResult {
+    /// Convert the value to its Postgres representation for the given type.
+    fn to_sql_value(&self, ty: &Type) -> Result<Box<dyn ToSql + Sync + Send>, BoxError> {
+        Ok(match &self.value {
+            ValueResult::DataList(l) => Box::new(l.clone()),
+            ValueResult::DataRecord(r) => Box::new(r.clone().map(CompositeParam)),
+            value => return to_scalar(value).to_sql_value(ty),
+        })
+    }
+}
+
 impl ToSql for VariableResult {
-    fn to_sql(
-        &self,
-        ty: &Type,
-        out: &mut tokio_postgres::types::private::BytesMut,
-    ) -> std::result::Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError>
     where
         Self: Sized,
     {
-        match &self.value {
-            ValueResult::DataBoolean(b) => b.to_sql(ty, out),
-            ValueResult::DataDecimal(b) => b.to_sql(ty, out),
-            ValueResult::DataInteger(b) => b.to_sql(ty, out),
-            ValueResult::DataString(b) => b.to_sql(ty, out),
-            ValueResult::DataTimestamp(b) => b.to_sql(ty, out),
-        }
+        self.to_sql_value(ty)?.to_sql_checked(ty, out)
     }
 
     fn accepts(ty: &Type) -> bool
     where
         Self: Sized,
     {
-        bool::accepts(ty) || f64::accepts(ty) || i64::accepts(ty) || String::accepts(ty)
+        ScalarResult::accepts(ty)
+            || <Vec<ScalarResult> as ToSql>::accepts(ty)
+            || CompositeParam::accepts(ty)
+    }
+
+    fn encode_format(&self, ty: &Type) -> Format {
+        self.to_sql_value(ty)
+            .map(|value| value.encode_format(ty))
+            .unwrap_or(Format::Binary)
     }
 
-    fn encode_format(&self, ty: &Type) -> tokio_postgres::types::Format {
-        match &self.value {
-            ValueResult::DataBoolean(b) => b.encode_format(ty),
-            ValueResult::DataDecimal(b) => b.encode_format(ty),
-            ValueResult::DataInteger(b) => b.encode_format(ty),
-            ValueResult::DataString(b) => b.encode_format(ty),
-            ValueResult::DataTimestamp(b) => b.encode_format(ty),
+    fn to_sql_checked(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        self.to_sql_value(ty)?.to_sql_checked(ty, out)
+    }
+}
+
+/// The single value held by a `ValueResult` that is not a list or a record.
+fn to_scalar(value: &ValueResult) -> ScalarResult {
+    let scalar = match value {
+        ValueResult::DataString(s) => s.clone().map(ScalarResult::ScalarString),
+        ValueResult::DataTimestamp(t) => t.map(ScalarResult::ScalarTimestamp),
+        ValueResult::DataBoolean(b) => b.map(ScalarResult::ScalarBoolean),
+        ValueResult::DataInteger(i) => i.map(ScalarResult::ScalarInteger),
+        ValueResult::DataDecimal(d) => d.map(ScalarResult::ScalarDecimal),
+        ValueResult::DataDate(d) => d.map(ScalarResult::ScalarDate),
+        ValueResult::DataTime(t) => t.map(ScalarResult::ScalarTime),
+        ValueResult::DataNumeric(n) => n.clone().map(ScalarResult::ScalarNumeric),
+        ValueResult::DataBytes(b) => b.clone().map(ScalarResult::ScalarBytes),
+        ValueResult::DataUuid(u) => u.clone().map(ScalarResult::ScalarUuid),
+        ValueResult::DataJson(j) => j.clone().map(ScalarResult::ScalarJson),
+        ValueResult::DataList(_) | ValueResult::DataRecord(_) => None,
+    };
+    scalar.unwrap_or(ScalarResult::ScalarNull)
+}
+
+impl ScalarResult {
+    /// Convert the value to its Postgres representation for the given type.
+    fn to_sql_value(&self, ty: &Type) -> Result<Box<dyn ToSql + Sync + Send>, BoxError> {
+        Ok(match self {
+            ScalarResult::ScalarNull => Box::new(Null),
+            ScalarResult::ScalarBoolean(b) => Box::new(*b),
+            ScalarResult::ScalarInteger(i) => match *ty {
+                Type::INT2 => Box::new(i16::try_from(*i)?),
+                Type::INT4 => Box::new(i32::try_from(*i)?),
+                Type::NUMERIC => Box::new(Numeric(i.to_string())),
+                _ => Box::new(*i),
+            },
+            ScalarResult::ScalarDecimal(d) => match *ty {
+                Type::FLOAT4 => Box::new(*d as f32),
+                Type::NUMERIC => Box::new(Numeric(d.to_string())),
+                _ => Box::new(*d),
+            },
+            ScalarResult::ScalarString(s) => Box::new(s.clone()),
+            ScalarResult::ScalarTimestamp(t) if *ty == Type::TIMESTAMPTZ => {
+                Box::new(t.to_datetime()?)
+            }
+            ScalarResult::ScalarTimestamp(t) => Box::new(t.to_naive()?),
+            ScalarResult::ScalarDate(d) => Box::new(date_from_days(*d)?),
+            ScalarResult::ScalarTime(t) => Box::new(time_from_micros(*t)?),
+            ScalarResult::ScalarNumeric(n) => Box::new(Numeric(n.clone())),
+            ScalarResult::ScalarBytes(b) => Box::new(b.clone()),
+            ScalarResult::ScalarUuid(u) if *ty == Type::UUID => Box::new(Uuid::parse_str(u)?),
+            ScalarResult::ScalarUuid(u) => Box::new(u.clone()),
+            ScalarResult::ScalarJson(j) if *ty == Type::JSON || *ty == Type::JSONB => {
+                Box::new(serde_json::from_str::<serde_json::Value>(j)?)
+            }
+            ScalarResult::ScalarJson(j) => Box::new(j.clone()),
+        })
+    }
+}
+
+impl ToSql for ScalarResult {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        self.to_sql_value(ty)?.to_sql_checked(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        Scalar::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
+
+/// SQL NULL, whatever the type.
+#[derive(Debug)]
+struct Null;
+
+impl ToSql for Null {
+    fn to_sql(&self, _ty: &Type, _out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+/// A scalar read from Postgres, as an array element or a record field.
+struct Scalar(ScalarResult);
+
+impl<'a> FromSql<'a> for Scalar {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        let scalar = match *ty {
+            Type::BOOL => ScalarResult::ScalarBoolean(bool::from_sql(ty, raw)?),
+            Type::INT2 => ScalarResult::ScalarInteger(i16::from_sql(ty, raw)?.into()),
+            Type::INT4 => ScalarResult::ScalarInteger(i32::from_sql(ty, raw)?.into()),
+            Type::INT8 => ScalarResult::ScalarInteger(i64::from_sql(ty, raw)?),
+            Type::FLOAT4 => ScalarResult::ScalarDecimal(f32::from_sql(ty, raw)?.into()),
+            Type::FLOAT8 => ScalarResult::ScalarDecimal(f64::from_sql(ty, raw)?),
+            Type::NUMERIC => ScalarResult::ScalarNumeric(Numeric::from_sql(ty, raw)?.0),
+            Type::TIMESTAMP => {
+                ScalarResult::ScalarTimestamp(NaiveDateTime::from_sql(ty, raw)?.into())
+            }
+            Type::TIMESTAMPTZ => {
+                ScalarResult::ScalarTimestamp(DateTime::<Utc>::from_sql(ty, raw)?.into())
+            }
+            Type::DATE => ScalarResult::ScalarDate(days_from_date(NaiveDate::from_sql(ty, raw)?)),
+            Type::TIME => ScalarResult::ScalarTime(micros_from_time(NaiveTime::from_sql(ty, raw)?)),
+            Type::BYTEA => ScalarResult::ScalarBytes(Vec::<u8>::from_sql(ty, raw)?),
+            Type::UUID => ScalarResult::ScalarUuid(Uuid::from_sql(ty, raw)?.to_string()),
+            Type::JSON | Type::JSONB => {
+                ScalarResult::ScalarJson(serde_json::Value::from_sql(ty, raw)?.to_string())
+            }
+            _ => ScalarResult::ScalarString(String::from_sql(ty, raw)?),
+        };
+        Ok(Scalar(scalar))
+    }
+
+    fn from_sql_null(_ty: &Type) -> Result<Self, BoxError> {
+        Ok(Scalar(ScalarResult::ScalarNull))
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(
+            *ty,
+            Type::BOOL
+                | Type::INT2
+                | Type::INT4
+                | Type::INT8
+                | Type::FLOAT4
+                | Type::FLOAT8
+                | Type::NUMERIC
+                | Type::TIMESTAMP
+                | Type::TIMESTAMPTZ
+                | Type::DATE
+                | Type::TIME
+                | Type::BYTEA
+                | Type::UUID
+                | Type::JSON
+                | Type::JSONB
+        ) || <String as FromSql>::accepts(ty)
+    }
+}
+
+/// A record read from Postgres, with scalar fields.
+struct Composite(Vec<FieldResult>);
+
+impl<'a> FromSql<'a> for Composite {
+    fn from_sql(ty: &Type, mut raw: &'a [u8]) -> Result<Self, BoxError> {
+        let Kind::Composite(fields) = ty.kind() else {
+            return Err(format!("{ty} is not a composite type").into());
+        };
+        if i32::from_be_bytes(take(&mut raw, 4)?.try_into()?) as usize != fields.len() {
+            return Err(format!("invalid number of fields for {ty}").into());
+        }
+        let mut values = Vec::with_capacity(fields.len());
+        for field in fields {
+            // Skip the field type oid, we know it already.
+            take(&mut raw, 4)?;
+            let len = i32::from_be_bytes(take(&mut raw, 4)?.try_into()?);
+            let value = match usize::try_from(len) {
+                Ok(len) => Some(take(&mut raw, len)?),
+                // Negative length is NULL.
+                Err(_) => None,
+            };
+            values.push(FieldResult {
+                name: field.name().to_string(),
+                value: Scalar::from_sql_nullable(field.type_(), value)?.0,
+            });
         }
+        Ok(Composite(values))
     }
 
-    fn to_sql_checked(
-        &self,
-        ty: &Type,
-        out: &mut tokio_postgres::types::private::BytesMut,
-    ) -> std::result::Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>>
-    {
-        match &self.value {
-            ValueResult::DataBoolean(b) => b.to_sql_checked(ty, out),
-            ValueResult::DataDecimal(b) => b.to_sql_checked(ty, out),
-            ValueResult::DataInteger(b) => b.to_sql_checked(ty, out),
-            ValueResult::DataString(b) => b.to_sql_checked(ty, out),
-            ValueResult::DataTimestamp(b) => b.to_sql_checked(ty, out),
+    fn accepts(ty: &Type) -> bool {
+        matches!(ty.kind(), Kind::Composite(fields) if fields.iter().all(|f| Scalar::accepts(f.type_())))
+    }
+}
+
+/// A record bound as a Postgres parameter, its fields matched by name.
+#[derive(Debug)]
+struct CompositeParam(Vec<FieldResult>);
+
+impl ToSql for CompositeParam {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+        let Kind::Composite(fields) = ty.kind() else {
+            return Err(format!("{ty} is not a composite type").into());
+        };
+        if let Some(unknown) = self
+            .0
+            .iter()
+            .find(|f| !fields.iter().any(|field| field.name() == f.name))
+        {
+            return Err(format!("{ty} has no field `{}`", unknown.name).into());
+        }
+        out.extend_from_slice(&i32::try_from(fields.len())?.to_be_bytes());
+        for field in fields {
+            out.extend_from_slice(&field.type_().oid().to_be_bytes());
+            // Missing fields are NULL.
+            let value = self
+                .0
+                .iter()
+                .find(|f| f.name == field.name())
+                .map(|f| &f.value)
+                .unwrap_or(&ScalarResult::ScalarNull);
+            // Write the value, then its length before it.
+            let base = out.len();
+            out.extend_from_slice(&[0; 4]);
+            let len = match value.to_sql_checked(field.type_(), out)? {
+                IsNull::Yes => -1,
+                IsNull::No => i32::try_from(out.len() - base - 4)?,
+            };
+            out[base..base + 4].copy_from_slice(&len.to_be_bytes());
+        }
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        matches!(ty.kind(), Kind::Composite(_))
+    }
+
+    to_sql_checked!();
+}
+
+/// Take the given number of bytes from the front of a buffer.
+fn take<'a>(raw: &mut &'a [u8], len: usize) -> Result<&'a [u8], BoxError> {
+    if raw.len() < len {
+        return Err("invalid buffer size".into());
+    }
+    let (head, tail) = raw.split_at(len);
+    *raw = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use anyhow::{anyhow, Result};
+    use tokio_postgres::types::Field;
+
+    use super::*;
+
+    fn connection(yaml: &str) -> Result<Box<PostgresPool>> {
+        match new_connection(serde_yaml::from_str(yaml)?, false)? {
+            DBConnection::PostgresConnection(pool) => Ok(pool),
+            _ => Err(anyhow!("not a postgres connection")),
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_transaction_panic() -> Result<()> {
+        let pool =
+            connection("config: host=localhost dbname=query_test user=postgres password=password")?;
+        let client = pool.pool().await?.get().await?;
+        let handle = Handle::current();
+        tokio::task::block_in_place(|| {
+            let res = catch_unwind(AssertUnwindSafe(|| {
+                transaction(
+                    &handle,
+                    &client,
+                    "BEGIN READ ONLY",
+                    || -> crate::Result<()> { panic!("plugin panicked") },
+                )
+            }));
+            assert!(res.is_err());
+        });
+        // The transaction was rolled back rather than left open.
+        let rows = client.query("SHOW transaction_read_only", &[]).await?;
+        assert_eq!("off", rows[0].get::<_, &str>(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_connection_options() -> Result<()> {
+        let pool = connection(
+            "config: host=localhost user=postgres
+pool:
+  min_size: 1
+  max_size: 8
+  idle_timeout: 60
+tls:
+  mode: require",
+        )?;
+        assert_eq!(Some(1), pool.options.min_size);
+        assert_eq!(Some(8), pool.options.max_size);
+        assert_eq!(Some(60), pool.options.idle_timeout);
+        assert!(pool.tls.is_some());
+        assert_eq!(SslMode::Require, pool.config.get_ssl_mode());
+
+        let pool = connection("config: host=localhost user=postgres")?;
+        assert_eq!(None, pool.options.max_size);
+        assert!(pool.tls.is_none());
+
+        let pool = connection("config: host=localhost user=postgres sslmode=require")?;
+        assert!(pool.tls.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_connection_errors() {
+        assert!(connection(
+            "config: host=localhost
+tls:
+  mode: verify-full
+  ca_path: does/not/exist.pem"
+        )
+        .is_err());
+        assert!(connection(
+            "config: host=localhost
+tls:
+  mode: sometimes"
+        )
+        .is_err());
+        assert!(connection(
+            "config: host=localhost
+pool:
+  size: 3"
+        )
+        .is_err());
+    }
+
+    fn point_type() -> Type {
+        Type::new(
+            "point2".into(),
+            0,
+            Kind::Composite(vec![
+                Field::new("x".into(), Type::INT4),
+                Field::new("label".into(), Type::TEXT),
+            ]),
+            "public".into(),
+        )
+    }
+
+    #[test]
+    fn test_array_round_trip() {
+        let list = vec![
+            ScalarResult::ScalarInteger(1),
+            ScalarResult::ScalarNull,
+            ScalarResult::ScalarInteger(3),
+        ];
+        let mut out = BytesMut::new();
+        list.to_sql_checked(&Type::INT4_ARRAY, &mut out).unwrap();
+        let read: Vec<Scalar> = FromSql::from_sql(&Type::INT4_ARRAY, &out).unwrap();
+        assert!(matches!(
+            read.as_slice(),
+            [
+                Scalar(ScalarResult::ScalarInteger(1)),
+                Scalar(ScalarResult::ScalarNull),
+                Scalar(ScalarResult::ScalarInteger(3))
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_composite_round_trip() {
+        let ty = point_type();
+        let record = CompositeParam(vec![FieldResult {
+            name: "x".into(),
+            value: ScalarResult::ScalarInteger(42),
+        }]);
+        let mut out = BytesMut::new();
+        record.to_sql_checked(&ty, &mut out).unwrap();
+        let read = Composite::from_sql(&ty, &out).unwrap();
+        assert_eq!(2, read.0.len());
+        assert_eq!("x", read.0[0].name);
+        assert!(matches!(read.0[0].value, ScalarResult::ScalarInteger(42)));
+        assert_eq!("label", read.0[1].name);
+        assert!(matches!(read.0[1].value, ScalarResult::ScalarNull));
+    }
+
+    #[test]
+    fn test_composite_unknown_field() {
+        let record = CompositeParam(vec![FieldResult {
+            name: "y".into(),
+            value: ScalarResult::ScalarInteger(42),
+        }]);
+        let mut out = BytesMut::new();
+        assert!(record.to_sql_checked(&point_type(), &mut out).is_err());
+    }
 }
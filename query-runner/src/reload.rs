@@ -0,0 +1,206 @@
+//! Reloading plugins and connections from local files.
+
+use std::{collections::HashMap, fmt::Display, sync::Arc};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use wasmer::Store;
+
+use crate::config::{compile_plugin, new_connection, read_connection_configs, read_plugin_files};
+use crate::error::Result;
+use crate::{
+    load_plugin_settings, ModuleCache, State, CONNECTIONS_FILE, PLUGINS_FOLDER, PLUGIN_CACHE,
+    PLUGIN_SETTINGS_FILE,
+};
+
+/// What plugins and connections were loaded from, to tell which ones a reload changes.
+#[derive(Default)]
+pub(crate) struct Sources {
+    /// Hash of the WASM code of each plugin.
+    plugins: HashMap<String, Vec<u8>>,
+    /// Configuration of each connection.
+    connections: HashMap<String, serde_yaml::Value>,
+    /// Settings of each plugin, as read from the settings file.
+    settings: HashMap<String, HashMap<String, String>>,
+}
+
+/// Names of the plugins, connections or plugin settings a reload added, changed and removed, sorted.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Changes {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Changes {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Display for Changes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return write!(f, "unchanged");
+        }
+        let parts: Vec<String> = [
+            ("added", &self.added),
+            ("changed", &self.changed),
+            ("removed", &self.removed),
+        ]
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(what, names)| format!("{what} {}", names.join(", ")))
+        .collect();
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+/// A state loaded again from local files, and what changed from the previous one.
+pub struct Reload {
+    pub state: State,
+    pub plugins: Changes,
+    pub connections: Changes,
+    /// Plugins whose settings changed.
+    pub settings: Changes,
+}
+
+impl State {
+    /// Load plugins, connections and plugin settings again from local files, into a new state with the
+    /// same engine, limits and batch size. Unchanged plugins and connections are kept as is: in-memory
+    /// databases keep their data, pools their connections, and only new or modified plugins are compiled.
+    /// Fails if any of them fails to load, or a plugin has invalid parameter constraints,
+    /// the current state can then still be used.
+    pub fn reload(&self) -> Result<Reload> {
+        let connection_configs = read_connection_configs(CONNECTIONS_FILE)?;
+        let (connections, connection_changes) = reuse_or_load(
+            &self.connections,
+            &self.sources.connections,
+            &connection_configs,
+            |name, config| Ok(Arc::new(new_connection(name, config.clone())?)),
+        )?;
+
+        let wasm = read_plugin_files(PLUGINS_FOLDER)?;
+        let digests: HashMap<String, Vec<u8>> = wasm
+            .iter()
+            .map(|(name, wasm)| (name.clone(), Sha256::digest(wasm).to_vec()))
+            .collect();
+        let store = Store::new(&self.engine);
+        let cache = ModuleCache::new(PLUGIN_CACHE, self.compiler);
+        let (plugins, plugin_changes) =
+            reuse_or_load(&self.plugins, &self.sources.plugins, &digests, |name, _| {
+                compile_plugin(&store, name, &wasm[name], Some(&cache))
+            })?;
+
+        let loaded_settings = load_plugin_settings(PLUGIN_SETTINGS_FILE)?;
+        // Settings are used as read, they are their own sources.
+        let (settings, setting_changes) = reuse_or_load(
+            &self.sources.settings,
+            &self.sources.settings,
+            &loaded_settings,
+            |_, settings| Ok(settings.clone()),
+        )?;
+
+        let mut state = State {
+            connections,
+            compiler: self.compiler,
+            engine: self.engine.clone(),
+            plugins,
+            limits: self.limits.clone(),
+            settings,
+            batch_size: self.batch_size,
+            sources: Sources {
+                plugins: digests,
+                connections: connection_configs,
+                settings: loaded_settings,
+            },
+            patterns: Default::default(),
+        };
+        state.patterns = state.compile_patterns()?;
+        Ok(Reload {
+            state,
+            plugins: plugin_changes,
+            connections: connection_changes,
+            settings: setting_changes,
+        })
+    }
+}
+
+/// Keep the current values whose source did not change, load the others, and tell what changed.
+fn reuse_or_load<T: Clone, S: PartialEq>(
+    current: &HashMap<String, T>,
+    current_sources: &HashMap<String, S>,
+    sources: &HashMap<String, S>,
+    mut load: impl FnMut(&str, &S) -> Result<T>,
+) -> Result<(HashMap<String, T>, Changes)> {
+    let mut values = HashMap::new();
+    let mut changes = Changes::default();
+    for (name, source) in sources.iter() {
+        let value = match current.get(name) {
+            Some(value) if current_sources.get(name) == Some(source) => value.clone(),
+            Some(_) => {
+                changes.changed.push(name.clone());
+                load(name, source)?
+            }
+            None => {
+                changes.added.push(name.clone());
+                load(name, source)?
+            }
+        };
+        values.insert(name.clone(), value);
+    }
+    changes.removed = current
+        .keys()
+        .filter(|name| !sources.contains_key(*name))
+        .cloned()
+        .collect();
+    changes.added.sort();
+    changes.changed.sort();
+    changes.removed.sort();
+    Ok((values, changes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reuse_or_load() -> Result<()> {
+        let current = HashMap::from([
+            ("kept".to_string(), 1),
+            ("changed".to_string(), 2),
+            ("removed".to_string(), 3),
+        ]);
+        let current_sources = HashMap::from([
+            ("kept".to_string(), "a"),
+            ("changed".to_string(), "b"),
+            ("removed".to_string(), "c"),
+        ]);
+        let sources = HashMap::from([
+            ("kept".to_string(), "a"),
+            ("changed".to_string(), "bb"),
+            ("added".to_string(), "d"),
+        ]);
+        let (values, changes) =
+            reuse_or_load(&current, &current_sources, &sources, |_, source| {
+                Ok(10 * source.len())
+            })?;
+        assert_eq!(
+            HashMap::from([
+                ("kept".to_string(), 1),
+                ("changed".to_string(), 20),
+                ("added".to_string(), 10),
+            ]),
+            values
+        );
+        assert_eq!(vec!["added"], changes.added);
+        assert_eq!(vec!["changed"], changes.changed);
+        assert_eq!(vec!["removed"], changes.removed);
+        assert_eq!(
+            "added added; changed changed; removed removed",
+            changes.to_string()
+        );
+        Ok(())
+    }
+}
@@ -1,53 +1,309 @@
 //! SQLLite implementation.
 
-use std::sync::Mutex;
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{anyhow, Result};
+use parking_lot::{Mutex, ReentrantMutex, ReentrantMutexGuard};
+use serde::Deserialize;
 use serde_yaml::Value;
+use tokio::sync::Semaphore;
 
-use crate::{add_result, parse::positional, DBConnection, ExecutionState, QueryResult, Variable};
+use crate::{
+    blocking::{spawn_execution, Emit},
+    error::{QueryRunnerError, Result},
+    numeric::validate_numeric,
+    parse::positional,
+    temporal::{date_from_days, days_from_date, micros_from_time, time_from_micros},
+    DBConnection, ExecutionState, QueryResult, ResultStream, Variable,
+};
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use uuid::Uuid;
 
+use rusqlite::types::FromSql;
 use rusqlite::*;
 
 use crate::query::*;
 
+/// Default maximum number of concurrent runs on a connection.
+const DEFAULT_POOL_SIZE: usize = 4;
+
+/// How long to wait for a database locked by another connection.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of the next in-memory database.
+static NEXT_MEMORY_DATABASE: AtomicU64 = AtomicU64::new(0);
+
 /// Create a new connection from a configuration value.
-pub(crate) fn new_connection(value: Value) -> Result<DBConnection> {
+pub(crate) fn new_connection(value: Value, read_only: bool) -> Result<DBConnection> {
     let path = value
         .get("path")
-        .ok_or(anyhow!("No path provided"))?
+        .ok_or(configuration("No path provided"))?
         .as_str()
-        .ok_or(anyhow!("path is not a string"))?;
-    if path == "memory" {
-        Ok(DBConnection::SqliteConnection(Mutex::from(
-            Connection::open_in_memory()?,
-        )))
+        .ok_or(configuration("path is not a string"))?;
+    let pool: PoolOptions = match value.get("pool") {
+        Some(pool) => serde_yaml::from_value(pool.clone())
+            .map_err(|err| configuration(format!("invalid pool: {err}")))?,
+        None => PoolOptions::default(),
+    };
+    let max_size = pool.max_size.map_or(DEFAULT_POOL_SIZE, NonZeroUsize::get);
+    let (path, in_memory) = if path == "memory" {
+        // Each connection has its own in-memory database, shared by the connections of its pool
+        // and kept as long as the primary one is open.
+        let database = NEXT_MEMORY_DATABASE.fetch_add(1, Ordering::Relaxed);
+        let id = std::process::id();
+        (
+            format!("file:query-runner-{id}-{database}?mode=memory&cache=shared"),
+            true,
+        )
+    } else {
+        (path.to_string(), false)
+    };
+    // The in-memory database is set up with the primary connection, so it is writable.
+    let primary = open(&path, read_only && !in_memory)?;
+    Ok(DBConnection::SqliteConnection(Arc::new(SqlitePool {
+        path,
+        in_memory,
+        read_only,
+        primary: Arc::new(ReentrantMutex::new(primary)),
+        idle: Mutex::new(Vec::new()),
+        permits: Arc::new(Semaphore::new(max_size)),
+    })))
+}
+
+/// A configuration error with the given message.
+fn configuration(message: impl Into<String>) -> QueryRunnerError {
+    QueryRunnerError::Configuration(message.into())
+}
+
+/// Pool settings, from the `pool` section of the connection.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolOptions {
+    /// Maximum number of concurrent runs.
+    max_size: Option<NonZeroUsize>,
+}
+
+/// Open a connection to a database file, or to an in-memory database by URI.
+fn open(path: &str, read_only: bool) -> Result<Connection> {
+    let connection = if read_only {
+        Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?
     } else {
-        Ok(DBConnection::SqliteConnection(Mutex::from(
-            Connection::open(path)?,
-        )))
+        Connection::open(path)?
+    };
+    connection.busy_timeout(BUSY_TIMEOUT)?;
+    Ok(connection)
+}
+
+/// Connections to a SQLite database.
+/// Runs each get their own connection, so they don't wait for each other.
+pub struct SqlitePool {
+    /// Path of the database file, or URI of the in-memory database.
+    path: String,
+    /// Whether the database is in memory, shared by the connections through the SQLite cache.
+    in_memory: bool,
+    /// Refuse write queries.
+    pub(crate) read_only: bool,
+    primary: SharedConnection,
+    idle: Mutex<Vec<Connection>>,
+    /// Limit the number of concurrent runs.
+    permits: Arc<Semaphore>,
+}
+
+/// A connection used by a run and by the queries its plugin makes itself, from the same thread.
+type SharedConnection = Arc<ReentrantMutex<Connection>>;
+
+impl SqlitePool {
+    /// Lock the primary connection, for example to set up the database.
+    /// It is writable even if the connection is read-only, when the database is in memory.
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, Connection> {
+        self.primary.lock()
+    }
+
+    /// Open a new connection for runs.
+    fn connect(&self) -> Result<Connection> {
+        if !self.in_memory {
+            return open(&self.path, self.read_only);
+        }
+        let connection = open(&self.path, false)?;
+        if self.read_only {
+            connection.pragma_update(None, "query_only", true)?;
+        }
+        Ok(connection)
+    }
+
+    /// Call the given function with a connection, blocking the current thread.
+    fn with_connection<T>(&self, f: impl FnOnce(&SharedConnection) -> Result<T>) -> Result<T> {
+        let idle = self.idle.lock().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self.connect()?,
+        };
+        let connection = Arc::new(ReentrantMutex::new(connection));
+        let res = f(&connection);
+        // Only reuse the connection if nothing kept it.
+        if let Ok(connection) = Arc::try_unwrap(connection) {
+            self.idle.lock().push(connection.into_inner());
+        }
+        res
     }
 }
 
-/// Execute a query and return the result.
-pub(crate) fn execute(
-    connection: &Mutex<Connection>,
+/// Execute a query on a blocking thread and stream the results back.
+pub(crate) async fn execute_stream(
+    pool: Arc<SqlitePool>,
+    mut state: ExecutionState,
+) -> Result<ResultStream<'static>> {
+    let permit = pool
+        .permits
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|err| QueryRunnerError::Internal(err.to_string()))?;
+    Ok(spawn_execution(move |emit| {
+        let res = pool.with_connection(|connection| {
+            let shared = connection.clone();
+            state.set_sub_query_runner(Some(Box::new(move |query, params| {
+                sub_query(&shared.lock(), query, params)
+            })));
+            let res = execute(&connection.lock(), &mut state, emit);
+            state.set_sub_query_runner(None);
+            res
+        });
+        drop(permit);
+        res
+    }))
+}
+
+/// Run a query of the host itself on a blocking thread, returning all its rows.
+pub(crate) async fn query(pool: Arc<SqlitePool>, query: &str) -> Result<QueryResult> {
+    let permit = pool
+        .permits
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|err| QueryRunnerError::Internal(err.to_string()))?;
+    let query = query.to_string();
+    tokio::task::spawn_blocking(move || {
+        let res = pool.with_connection(|connection| sub_query(&connection.lock(), &query, &[]));
+        drop(permit);
+        res
+    })
+    .await
+    .map_err(|err| QueryRunnerError::Internal(err.to_string()))?
+}
+
+/// Execute the queries of a plugin, sending each intermediate result to the given callback.
+/// The queries of a write plugin run in a transaction, only committed if they all succeed.
+fn execute(connection: &Connection, state: &mut ExecutionState, emit: Emit<'_>) -> Result<()> {
+    if state.mode == QueryMode::Write {
+        // Rolled back when dropped.
+        let transaction = connection.unchecked_transaction()?;
+        execute_queries(connection, state, emit)?;
+        transaction.commit()?;
+        return Ok(());
+    }
+    execute_queries(connection, state, emit)
+}
+
+/// Execute the query of a plugin and the ones it chains.
+fn execute_queries(
+    connection: &Connection,
     state: &mut ExecutionState,
-) -> Result<Option<QueryResult>> {
+    emit: Emit<'_>,
+) -> Result<()> {
     // Get the query SQL.
-    let query = state
-        .query
-        .execution_query_string(&mut state.store, &state.execution)?;
+    let mut query = state.query_string()?;
     // Get parameters.
-    let params = state
-        .query
-        .execution_variables(&mut state.store, &state.execution)?;
+    let mut params = state.variables()?;
 
-    let query = positional("?", 1, &query, &params);
+    loop {
+        execute_one(connection, state, &query, &params, emit)?;
+        match state.next()? {
+            Some(next) => (query, params) = (next.query_string, next.variables),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Execute one query, sending each intermediate result to the given callback.
+fn execute_one(
+    connection: &Connection,
+    state: &mut ExecutionState,
+    query: &str,
+    params: &[VariableResult],
+    emit: Emit<'_>,
+) -> Result<()> {
+    let (mut stmt, mut columns, kinds) = prepare(connection, query, params)?;
+    if state.mode == QueryMode::Write {
+        let count = stmt.raw_execute()?;
+        // Send affected row count to plugin.
+        if let Some(res) = state.affected(count as u64)? {
+            emit(res)?;
+        }
+    } else {
+        state.columns(&columns)?;
+        let mut rows = stmt.raw_query();
+        // Loop through all rows.
+        while let Some(row) = rows.next()? {
+            // Build row.
+            let values = row_values(row, &kinds, &mut columns)?;
+            let result_one = columns
+                .iter()
+                .zip(values)
+                .map(|(column, value)| Variable {
+                    name: &column.name,
+                    value,
+                })
+                .collect();
+            // Send row to plugin.
+            if let Some(res) = state.row(result_one)? {
+                emit(res)?;
+            }
+        }
+        // Send the last batch, if any.
+        if let Some(res) = state.flush_rows()? {
+            emit(res)?;
+        }
+    }
+    // End.
+    if let Some(res) = state.end(&columns)? {
+        emit(res)?;
+    }
+    Ok(())
+}
+
+/// Run a query made by the plugin itself, returning all its rows.
+fn sub_query(
+    connection: &Connection,
+    query: &str,
+    params: &[VariableResult],
+) -> Result<QueryResult> {
+    let (mut stmt, mut columns, kinds) = prepare(connection, query, params)?;
+    let mut rows = stmt.raw_query();
+    let mut values = Vec::new();
+    while let Some(row) = rows.next()? {
+        values.push(row_values(row, &kinds, &mut columns)?);
+    }
+    Ok(QueryResult { columns, values })
+}
+
+/// Prepare a query and bind its parameters, returning the statement with the description and kind of its columns.
+fn prepare<'c>(
+    connection: &'c Connection,
+    query: &str,
+    params: &[VariableResult],
+) -> Result<(Statement<'c>, Vec<ColumnResult>, Vec<ColumnKind>)> {
+    let query = positional("?", 1, query, params);
 
-    let connection = connection.lock().unwrap();
     // Prepare statement.
-    let mut stmt = connection.prepare(&query).map_err(|op| anyhow!(op))?;
+    let mut stmt = connection.prepare(&query)?;
     // Bind parameters.
     for (idx, param) in params.iter().enumerate() {
         match &param.value {
@@ -55,58 +311,328 @@ pub(crate) fn execute(
             ValueResult::DataDecimal(Some(d)) => stmt.raw_bind_parameter(idx + 1, d)?,
             ValueResult::DataInteger(Some(i)) => stmt.raw_bind_parameter(idx + 1, i)?,
             ValueResult::DataString(Some(s)) => stmt.raw_bind_parameter(idx + 1, s)?,
-            ValueResult::DataTimestamp(Some(t)) => stmt.raw_bind_parameter(idx + 1, t)?,
+            ValueResult::DataTimestamp(Some(t)) if t.offset_seconds.is_some() => {
+                stmt.raw_bind_parameter(idx + 1, t.to_datetime()?)?
+            }
+            ValueResult::DataTimestamp(Some(t)) => {
+                stmt.raw_bind_parameter(idx + 1, t.to_naive()?)?
+            }
+            ValueResult::DataDate(Some(d)) => {
+                stmt.raw_bind_parameter(idx + 1, date_from_days(*d)?)?
+            }
+            ValueResult::DataTime(Some(t)) => {
+                stmt.raw_bind_parameter(idx + 1, time_from_micros(*t)?)?
+            }
+            ValueResult::DataNumeric(Some(n)) => stmt.raw_bind_parameter(idx + 1, n)?,
+            ValueResult::DataBytes(Some(b)) => stmt.raw_bind_parameter(idx + 1, b)?,
+            ValueResult::DataUuid(Some(u)) => stmt.raw_bind_parameter(idx + 1, u)?,
+            ValueResult::DataJson(Some(j)) => stmt.raw_bind_parameter(idx + 1, j)?,
+            ValueResult::DataList(Some(_)) | ValueResult::DataRecord(Some(_)) => {
+                return Err(QueryRunnerError::Parameter(format!(
+                    "SQLite does not support list or record parameters like `{}`",
+                    param.name
+                )))
+            }
             // Unbound parameters default to NULL.
             _ => {}
         }
     }
-    // Get columns name and type.
-    let columns: Vec<(String, String)> = stmt
+    // Get columns name and kind.
+    let (columns, kinds): (Vec<ColumnResult>, Vec<ColumnKind>) = stmt
         .columns()
         .iter()
         .map(|c| {
-            (
-                c.name().to_string(),
-                c.decl_type().unwrap_or_default().to_string(),
-            )
+            let kind = column_kind(c.decl_type());
+            let column = ColumnResult {
+                name: c.name().to_string(),
+                column_type: kind.column_type(),
+                // SQLite does not tell for query results.
+                nullable: None,
+                db_type: c.decl_type().map(str::to_string),
+            };
+            (column, kind)
         })
-        .collect();
-    let mut rows = stmt.raw_query();
-    // final result.
-    let mut result = Option::None;
-    // Loop through all rows.
-    while let Some(row) = rows.next()? {
-        // Build row.
-        let mut result_one = Vec::with_capacity(columns.len());
-        for (ix, (name, typ)) in columns.iter().enumerate() {
-            match typ.as_str() {
-                "INTEGER" => result_one.push(Variable {
-                    name,
-                    value: ValueResult::DataInteger(row.get(ix)?),
-                }),
-                "TEXT" => result_one.push(Variable {
-                    name,
-                    value: ValueResult::DataString(row.get(ix)?),
-                }),
-                "BOOL" => result_one.push(Variable {
-                    name,
-                    value: ValueResult::DataBoolean(row.get(ix)?),
-                }),
-                "REAL" => result_one.push(Variable {
-                    name,
-                    value: ValueResult::DataDecimal(row.get(ix)?),
-                }),
-                _ => return Err(anyhow!("unsupported type {typ}")),
+        .unzip();
+    Ok((stmt, columns, kinds))
+}
+
+/// How to read the values of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColumnKind {
+    Integer,
+    Text,
+    Boolean,
+    Real,
+    Numeric,
+    Timestamp,
+    Date,
+    Time,
+    Blob,
+    Uuid,
+    Json,
+    /// No declared type, use the storage class of each value.
+    Dynamic,
+}
+
+impl ColumnKind {
+    /// Logical type of the values read for this kind.
+    fn column_type(self) -> ColumnType {
+        match self {
+            ColumnKind::Integer => ColumnType::TypeInteger,
+            ColumnKind::Text => ColumnType::TypeString,
+            ColumnKind::Boolean => ColumnType::TypeBoolean,
+            ColumnKind::Real => ColumnType::TypeDecimal,
+            ColumnKind::Numeric => ColumnType::TypeNumeric,
+            ColumnKind::Timestamp => ColumnType::TypeTimestamp,
+            ColumnKind::Date => ColumnType::TypeDate,
+            ColumnKind::Time => ColumnType::TypeTime,
+            ColumnKind::Blob => ColumnType::TypeBytes,
+            ColumnKind::Uuid => ColumnType::TypeUuid,
+            ColumnKind::Json => ColumnType::TypeJson,
+            ColumnKind::Dynamic => ColumnType::TypeUnknown,
+        }
+    }
+}
+
+/// Get the kind of a column from its declared type, following the SQLite type affinity rules
+/// (see <https://www.sqlite.org/datatype3.html#determination_of_column_affinity>)
+/// once the types we give a more specific meaning to are handled.
+fn column_kind(decl_type: Option<&str>) -> ColumnKind {
+    let Some(decl_type) = decl_type else {
+        return ColumnKind::Dynamic;
+    };
+    let typ = decl_type.to_ascii_uppercase();
+    // Ignore size or precision, as in `VARCHAR(20)` or `DECIMAL(10,2)`.
+    let base = typ.split('(').next().unwrap_or_default().trim();
+    match base {
+        "" => ColumnKind::Dynamic,
+        "BOOL" | "BOOLEAN" => ColumnKind::Boolean,
+        "DECIMAL" | "NUMERIC" => ColumnKind::Numeric,
+        "TIMESTAMP" | "DATETIME" => ColumnKind::Timestamp,
+        "DATE" => ColumnKind::Date,
+        "TIME" => ColumnKind::Time,
+        "UUID" => ColumnKind::Uuid,
+        "JSON" => ColumnKind::Json,
+        t if t.contains("INT") => ColumnKind::Integer,
+        t if t.contains("CHAR") || t.contains("CLOB") || t.contains("TEXT") => ColumnKind::Text,
+        t if t.contains("BLOB") => ColumnKind::Blob,
+        t if t.contains("REAL") || t.contains("FLOA") || t.contains("DOUB") => ColumnKind::Real,
+        _ => ColumnKind::Numeric,
+    }
+}
+
+/// Read the values of a row according to the kinds of their columns.
+/// SQLite lets any column hold any value: values that do not match the kind of their column are
+/// read according to their storage class, and their column is then reported as `TypeUnknown`.
+fn row_values(
+    row: &rusqlite::Row,
+    kinds: &[ColumnKind],
+    columns: &mut [ColumnResult],
+) -> Result<Vec<ValueResult>> {
+    let mut values = Vec::with_capacity(kinds.len());
+    for (ix, kind) in kinds.iter().enumerate() {
+        let value = row.get_ref(ix)?;
+        values.push(typed_value(*kind, value).unwrap_or_else(|| {
+            columns[ix].column_type = ColumnType::TypeUnknown;
+            dynamic_value(value)
+        }));
+    }
+    Ok(values)
+}
+
+/// Read a value according to the kind of its column, `None` if it does not match it.
+fn typed_value(kind: ColumnKind, value: types::ValueRef) -> Option<ValueResult> {
+    let typed = match kind {
+        ColumnKind::Integer => FromSql::column_result(value).map(ValueResult::DataInteger),
+        ColumnKind::Text => FromSql::column_result(value).map(ValueResult::DataString),
+        ColumnKind::Boolean => FromSql::column_result(value).map(ValueResult::DataBoolean),
+        ColumnKind::Real => FromSql::column_result(value).map(ValueResult::DataDecimal),
+        ColumnKind::Numeric => numeric_value(value),
+        ColumnKind::Timestamp => Option::<NaiveDateTime>::column_result(value)
+            .map(|t| ValueResult::DataTimestamp(t.map(Timestamp::from))),
+        ColumnKind::Date => Option::<NaiveDate>::column_result(value)
+            .map(|d| ValueResult::DataDate(d.map(days_from_date))),
+        ColumnKind::Time => Option::<NaiveTime>::column_result(value)
+            .map(|t| ValueResult::DataTime(t.map(micros_from_time))),
+        ColumnKind::Blob => FromSql::column_result(value).map(ValueResult::DataBytes),
+        ColumnKind::Uuid => uuid_value(value),
+        ColumnKind::Json => FromSql::column_result(value).map(ValueResult::DataJson),
+        ColumnKind::Dynamic => Err(types::FromSqlError::InvalidType),
+    };
+    typed.ok()
+}
+
+/// Read a value according to its storage class.
+fn dynamic_value(value: types::ValueRef) -> ValueResult {
+    match value {
+        types::ValueRef::Null => ValueResult::DataString(None),
+        types::ValueRef::Integer(i) => ValueResult::DataInteger(Some(i)),
+        types::ValueRef::Real(d) => ValueResult::DataDecimal(Some(d)),
+        types::ValueRef::Text(t) => match std::str::from_utf8(t) {
+            Ok(s) => ValueResult::DataString(Some(s.to_string())),
+            Err(_) => ValueResult::DataBytes(Some(t.to_vec())),
+        },
+        types::ValueRef::Blob(b) => ValueResult::DataBytes(Some(b.to_vec())),
+    }
+}
+
+/// Read an exact numeric value, whatever storage class SQLite chose for it.
+fn numeric_value(value: types::ValueRef) -> types::FromSqlResult<ValueResult> {
+    let numeric = match value {
+        types::ValueRef::Null => None,
+        types::ValueRef::Integer(i) => Some(i.to_string()),
+        types::ValueRef::Real(d) => Some(d.to_string()),
+        types::ValueRef::Text(_) => Some(
+            validate_numeric(value.as_str()?)
+                .map_err(|_| types::FromSqlError::InvalidType)?
+                .to_string(),
+        ),
+        types::ValueRef::Blob(_) => return Err(types::FromSqlError::InvalidType),
+    };
+    Ok(ValueResult::DataNumeric(numeric))
+}
+
+/// Read a UUID stored either as text or as 16 bytes, in its canonical form.
+fn uuid_value(value: types::ValueRef) -> types::FromSqlResult<ValueResult> {
+    let uuid = match value {
+        types::ValueRef::Null => return Ok(ValueResult::DataUuid(None)),
+        types::ValueRef::Text(_) => Uuid::parse_str(value.as_str()?),
+        types::ValueRef::Blob(b) => Uuid::from_slice(b),
+        _ => return Err(types::FromSqlError::InvalidType),
+    };
+    uuid.map(|u| ValueResult::DataUuid(Some(u.to_string())))
+        .map_err(|_| types::FromSqlError::InvalidType)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use anyhow::{anyhow, Result};
+
+    use super::*;
+
+    #[test]
+    fn test_pool_file() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("query-runner-pool-{}.db", std::process::id()));
+        let value = serde_yaml::from_str(&format!("path: {}", path.display()))?;
+        let DBConnection::SqliteConnection(pool) = new_connection(value, false)? else {
+            return Err(anyhow!("not a SQLite connection"));
+        };
+        pool.lock()
+            .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?;
+        // Nested runs each get their own connection instead of waiting for each other.
+        let count = pool.with_connection(|c1| {
+            pool.with_connection(|c2| {
+                let x1: i64 = c1.lock().query_row("SELECT x FROM t", (), |r| r.get(0))?;
+                let x2: i64 = c2.lock().query_row("SELECT x FROM t", (), |r| r.get(0))?;
+                Ok(x1 + x2)
+            })
+        })?;
+        assert_eq!(2, count);
+        assert_eq!(2, pool.idle.lock().len());
+        drop(pool);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_pool_options() -> Result<()> {
+        let value = serde_yaml::from_str("path: memory\npool:\n  max_size: 2")?;
+        let DBConnection::SqliteConnection(pool) = new_connection(value, false)? else {
+            return Err(anyhow!("not a SQLite connection"));
+        };
+        assert_eq!(2, pool.permits.available_permits());
+        for pool in ["max_size: 0", "max_size: many", "size: 2"] {
+            let value = serde_yaml::from_str(&format!("path: memory\npool:\n  {pool}"))?;
+            assert!(new_connection(value, false).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("query-runner-read-only-{}.db", std::process::id()));
+        Connection::open(&path)?
+            .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?;
+        for path in [path.display().to_string(), "memory".to_string()] {
+            let value = serde_yaml::from_str(&format!("path: {path}"))?;
+            let DBConnection::SqliteConnection(pool) = new_connection(value, true)? else {
+                return Err(anyhow!("not a SQLite connection"));
+            };
+            if path == "memory" {
+                pool.lock()
+                    .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?;
             }
+            let count = pool.with_connection(|c| {
+                let c = c.lock();
+                assert!(c.execute("DELETE FROM t", ()).is_err());
+                assert!(c.execute_batch("DROP TABLE t").is_err());
+                Ok(c.query_row("SELECT COUNT(*) FROM t", (), |r| r.get::<_, i64>(0))?)
+            })?;
+            assert_eq!(1, count);
         }
-        // Send row to plugin.
-        let res = state.row(result_one)?;
-        result = add_result(result, res);
+        std::fs::remove_file(path)?;
+
+        // The in-memory database is writable again after a run, even a failed one.
+        let value = serde_yaml::from_str("path: memory")?;
+        let DBConnection::SqliteConnection(pool) = new_connection(value, true)? else {
+            return Err(anyhow!("not a SQLite connection"));
+        };
+        let res = catch_unwind(AssertUnwindSafe(|| {
+            pool.with_connection::<()>(|_| panic!("the run failed"))
+        }));
+        assert!(res.is_err());
+        pool.lock()
+            .execute_batch("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1);")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_column_kind() {
+        assert_eq!(ColumnKind::Dynamic, column_kind(None));
+        assert_eq!(ColumnKind::Dynamic, column_kind(Some("")));
+        assert_eq!(ColumnKind::Integer, column_kind(Some("INTEGER")));
+        assert_eq!(ColumnKind::Integer, column_kind(Some("int")));
+        assert_eq!(ColumnKind::Integer, column_kind(Some("UNSIGNED BIG INT")));
+        assert_eq!(ColumnKind::Text, column_kind(Some("VARCHAR(20)")));
+        assert_eq!(ColumnKind::Text, column_kind(Some("NATIVE CHARACTER(70)")));
+        assert_eq!(ColumnKind::Text, column_kind(Some("CLOB")));
+        assert_eq!(ColumnKind::Blob, column_kind(Some("BLOB")));
+        assert_eq!(ColumnKind::Real, column_kind(Some("DOUBLE PRECISION")));
+        assert_eq!(ColumnKind::Real, column_kind(Some("FLOAT")));
+        assert_eq!(ColumnKind::Numeric, column_kind(Some("DECIMAL(10,5)")));
+        assert_eq!(ColumnKind::Numeric, column_kind(Some("MONEY")));
+        assert_eq!(ColumnKind::Boolean, column_kind(Some("BOOLEAN")));
+        assert_eq!(ColumnKind::Timestamp, column_kind(Some("datetime")));
+    }
+
+    #[test]
+    fn test_typed_value() {
+        assert!(matches!(
+            typed_value(ColumnKind::Integer, types::ValueRef::Integer(1)),
+            Some(ValueResult::DataInteger(Some(1)))
+        ));
+        assert!(matches!(
+            typed_value(ColumnKind::Integer, types::ValueRef::Null),
+            Some(ValueResult::DataInteger(None))
+        ));
+        assert!(typed_value(ColumnKind::Integer, types::ValueRef::Text(b"abc")).is_none());
+        assert!(typed_value(ColumnKind::Numeric, types::ValueRef::Text(b"abc")).is_none());
+        assert!(typed_value(ColumnKind::Dynamic, types::ValueRef::Real(1.5)).is_none());
+        assert!(matches!(
+            dynamic_value(types::ValueRef::Text(b"abc")),
+            ValueResult::DataString(Some(s)) if s == "abc"
+        ));
+        assert!(matches!(
+            dynamic_value(types::ValueRef::Real(1.5)),
+            ValueResult::DataDecimal(Some(d)) if d == 1.5
+        ));
+        assert!(matches!(
+            dynamic_value(types::ValueRef::Null),
+            ValueResult::DataString(None)
+        ));
     }
-    // End.
-    let names: Vec<&str> = columns.iter().map(|(n, _)| n.as_str()).collect();
-    let end = state
-        .query
-        .execution_end(&mut state.store, &state.execution, &names)?;
-    Ok(add_result(result, end))
 }
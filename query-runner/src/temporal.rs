@@ -0,0 +1,184 @@
+//! Date and time utilities.
+
+use std::fmt::Display;
+
+use chrono::{
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, Offset, TimeZone,
+    Timelike,
+};
+
+use crate::error::{QueryRunnerError, Result};
+use crate::query::Timestamp;
+
+/// Number of days between the start of the common era and the Unix epoch.
+const UNIX_EPOCH_DAYS_FROM_CE: i32 = 719_163;
+
+/// Format of local date times.
+const NAIVE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+impl Timestamp {
+    /// As a time zone aware date time, local date times being considered UTC.
+    pub fn to_datetime(&self) -> Result<DateTime<FixedOffset>> {
+        let offset =
+            FixedOffset::east_opt(self.offset_seconds.unwrap_or_default()).ok_or_else(|| {
+                QueryRunnerError::Parameter(format!("invalid UTC offset {:?}", self.offset_seconds))
+            })?;
+        let utc = DateTime::from_timestamp_micros(self.micros).ok_or_else(|| {
+            QueryRunnerError::Parameter(format!("timestamp {} out of range", self.micros))
+        })?;
+        Ok(utc.with_timezone(&offset))
+    }
+
+    /// As a local date time, in the time zone of the timestamp.
+    pub fn to_naive(&self) -> Result<NaiveDateTime> {
+        Ok(self.to_datetime()?.naive_local())
+    }
+}
+
+impl From<NaiveDateTime> for Timestamp {
+    fn from(dt: NaiveDateTime) -> Self {
+        Timestamp {
+            micros: dt.and_utc().timestamp_micros(),
+            offset_seconds: None,
+        }
+    }
+}
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for Timestamp {
+    fn from(dt: DateTime<Tz>) -> Self {
+        Timestamp {
+            micros: dt.timestamp_micros(),
+            offset_seconds: Some(dt.offset().fix().local_minus_utc()),
+        }
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.offset_seconds, self.to_datetime()) {
+            (Some(_), Ok(dt)) => write!(f, "{}", dt.to_rfc3339()),
+            (None, Ok(dt)) => write!(f, "{}", dt.naive_local().format(NAIVE_FORMAT)),
+            (_, Err(_)) => write!(f, "<invalid timestamp {}>", self.micros),
+        }
+    }
+}
+
+/// Convert days since the Unix epoch to a date.
+pub(crate) fn date_from_days(days: i32) -> Result<NaiveDate> {
+    days.checked_add(UNIX_EPOCH_DAYS_FROM_CE)
+        .and_then(NaiveDate::from_num_days_from_ce_opt)
+        .ok_or_else(|| QueryRunnerError::Parameter(format!("date {days} out of range")))
+}
+
+/// Convert a date to days since the Unix epoch.
+pub(crate) fn days_from_date(date: NaiveDate) -> i32 {
+    date.num_days_from_ce() - UNIX_EPOCH_DAYS_FROM_CE
+}
+
+/// Convert microseconds since midnight to a time.
+pub(crate) fn time_from_micros(micros: i64) -> Result<NaiveTime> {
+    u32::try_from(micros.div_euclid(1_000_000))
+        .ok()
+        .and_then(|secs| {
+            NaiveTime::from_num_seconds_from_midnight_opt(
+                secs,
+                micros.rem_euclid(1_000_000) as u32 * 1_000,
+            )
+        })
+        .ok_or_else(|| QueryRunnerError::Parameter(format!("time {micros} out of range")))
+}
+
+/// Convert a time to microseconds since midnight.
+pub(crate) fn micros_from_time(time: NaiveTime) -> i64 {
+    i64::from(time.num_seconds_from_midnight()) * 1_000_000 + i64::from(time.nanosecond() / 1_000)
+}
+
+/// Format days since the Unix epoch as an ISO-8601 date.
+pub(crate) fn format_date(days: i32) -> String {
+    match date_from_days(days) {
+        Ok(date) => date.format("%Y-%m-%d").to_string(),
+        Err(_) => format!("<invalid date {days}>"),
+    }
+}
+
+/// Format microseconds since midnight as an ISO-8601 time.
+pub(crate) fn format_time(micros: i64) -> String {
+    match time_from_micros(micros) {
+        Ok(time) => time.format("%H:%M:%S%.f").to_string(),
+        Err(_) => format!("<invalid time {micros}>"),
+    }
+}
+
+/// Parse an ISO-8601 timestamp, with or without UTC offset.
+pub(crate) fn parse_timestamp(value: &str) -> Result<Timestamp> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.into());
+    }
+    NaiveDateTime::parse_from_str(value, NAIVE_FORMAT)
+        .or_else(|_| NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S%.f"))
+        .map(Timestamp::from)
+        .map_err(|_| QueryRunnerError::Parameter(format!("`{value}` is not an ISO-8601 timestamp")))
+}
+
+/// Parse an ISO-8601 date into days since the Unix epoch.
+pub(crate) fn parse_date(value: &str) -> Result<i32> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(days_from_date)
+        .map_err(|_| QueryRunnerError::Parameter(format!("`{value}` is not an ISO-8601 date")))
+}
+
+/// Parse an ISO-8601 time into microseconds since midnight.
+pub(crate) fn parse_time(value: &str) -> Result<i64> {
+    NaiveTime::parse_from_str(value, "%H:%M:%S%.f")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M"))
+        .map(micros_from_time)
+        .map_err(|_| QueryRunnerError::Parameter(format!("`{value}` is not an ISO-8601 time")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() -> Result<()> {
+        let t = parse_timestamp("2023-04-21T10:30:00Z")?;
+        assert_eq!(1_682_073_000_000_000, t.micros);
+        assert_eq!(Some(0), t.offset_seconds);
+        assert_eq!("2023-04-21T10:30:00+00:00", t.to_string());
+
+        let t = parse_timestamp("2023-04-21T12:30:00.5+02:00")?;
+        assert_eq!(1_682_073_000_500_000, t.micros);
+        assert_eq!(Some(7200), t.offset_seconds);
+        assert_eq!("2023-04-21T12:30:00.500+02:00", t.to_string());
+
+        let t = parse_timestamp("2023-04-21 10:30:00")?;
+        assert_eq!(1_682_073_000_000_000, t.micros);
+        assert_eq!(None, t.offset_seconds);
+        assert_eq!("2023-04-21T10:30:00", t.to_string());
+
+        assert!(parse_timestamp("2023-04-21").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_date() -> Result<()> {
+        assert_eq!(0, parse_date("1970-01-01")?);
+        assert_eq!(19_468, parse_date("2023-04-21")?);
+        assert_eq!(-1, parse_date("1969-12-31")?);
+        assert_eq!("2023-04-21", format_date(19_468));
+        assert!(parse_date("2023-02-30").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_time() -> Result<()> {
+        assert_eq!(0, parse_time("00:00:00")?);
+        assert_eq!(37_800_000_000, parse_time("10:30")?);
+        assert_eq!(37_800_250_000, parse_time("10:30:00.25")?);
+        assert_eq!("10:30:00.250", format_time(37_800_250_000));
+        assert!(parse_time("25:00:00").is_err());
+        assert!(time_from_micros(-1).is_err());
+        Ok(())
+    }
+}
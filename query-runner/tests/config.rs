@@ -15,6 +15,8 @@ fn load_connections_from_file() -> Result<()> {
         connections.get("postgres1"),
         Some(DBConnection::PostgresConnection(_))
     ));
+    assert!(!connections["memory"].read_only());
+    assert!(connections["memory_read_only"].read_only());
     Ok(())
 }
 
@@ -26,3 +28,26 @@ fn load_plugins_from_file() -> Result<()> {
     assert!(plugins.contains_key("test_collect2"));
     Ok(())
 }
+
+#[test]
+fn load_cached_plugins_from_file() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("query-runner-cache-{}", std::process::id()));
+    let cache = ModuleCache::new(&dir, Compiler::default());
+    let engine = build_engine();
+    // Compiled then loaded from the cache.
+    for _ in 0..2 {
+        let plugins = load_cached_plugins(&engine, "plugins", &cache)?;
+        assert!(plugins.contains_key("test_collect"));
+        assert!(plugins.contains_key("test_collect2"));
+    }
+    assert_eq!(4, std::fs::read_dir(&dir)?.count());
+
+    // Invalid cached modules are compiled again.
+    for entry in std::fs::read_dir(&dir)? {
+        std::fs::write(entry?.path(), b"invalid")?;
+    }
+    let plugins = load_cached_plugins(&engine, "plugins", &cache)?;
+    assert!(plugins.contains_key("test_collect"));
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
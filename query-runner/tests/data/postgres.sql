@@ -14,3 +14,44 @@ CREATE TABLE Users (
 INSERT INTO Users (username, name, email) VALUES 
     ('john', 'John Doe', 'john.doe@example.com'), 
     ('jane', 'Jane Doe', NULL);
+
+DROP TABLE IF EXISTS Orders;
+
+CREATE TABLE Orders (
+    order_id     BIGINT PRIMARY KEY,
+    customer_id  BIGINT NOT NULL
+);
+
+INSERT INTO Orders (order_id, customer_id) VALUES
+    (1234, 123),
+    (1235, 123);
+
+DROP TABLE IF EXISTS OrderNotes;
+
+CREATE TABLE OrderNotes (
+    order_id  BIGINT PRIMARY KEY,
+    note      TEXT NOT NULL
+);
+
+INSERT INTO OrderNotes (order_id, note) VALUES
+    (1234, 'urgent');
+
+DROP TABLE IF EXISTS OrderDetails;
+DROP TYPE IF EXISTS Address;
+
+CREATE TYPE Address AS (
+    street  TEXT,
+    city    TEXT
+);
+
+CREATE TABLE OrderDetails (
+    order_id    BIGINT PRIMARY KEY,
+    tags        TEXT[] NOT NULL,
+    quantities  INT4[],
+    address     Address
+);
+
+INSERT INTO OrderDetails (order_id, tags, quantities, address) VALUES
+    (1234, ARRAY['urgent', 'gift'], ARRAY[1, 2], ROW('1 Main St', 'Springfield')),
+    (1235, ARRAY[]::TEXT[], NULL, ROW('2 High St', NULL)),
+    (1236, ARRAY['bulk'], ARRAY[100], NULL);
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
 
 use anyhow::{anyhow, Result};
+use futures_util::TryStreamExt;
 use query_runner::{
-    query::ParameterType, DBConnection, QueryResult, State, ValueParam, ValueResult,
+    query::{ColumnType, ParameterType},
+    Compiler, DBConnection, FieldParam, FieldResult, ParameterValue, QueryResult, QueryRunnerError,
+    ScalarParam, ScalarResult, State, Timestamp, ValueParam, ValueResult, VariableParam,
 };
 
 #[test]
@@ -15,7 +18,50 @@ fn ok_module() -> Result<()> {
 #[test]
 fn err_module() -> Result<()> {
     let st = test_state()?;
-    assert!(st.get_plugin("test_collect_missing").is_err());
+    assert!(matches!(
+        st.get_plugin("test_collect_missing"),
+        Err(QueryRunnerError::PluginMissing(_))
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn run_errors() -> Result<()> {
+    let st = test_state()?;
+    let err = st
+        .run_untyped("test_collect", "memory", &HashMap::new())
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QueryRunnerError::Parameter(_)));
+    assert_eq!(
+        "no value provided for parameter `customer_id`",
+        err.to_string()
+    );
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let err = st
+        .run_untyped("test_collect", "missing", &variables)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QueryRunnerError::ConnectionMissing(_)));
+    Ok(())
+}
+
+#[tokio::test]
+async fn plugin_error() -> Result<()> {
+    let st = test_state()?;
+    let variables = HashMap::from([("customer_id", "-1")]);
+    let err = st
+        .run_untyped("test_collect", "memory", &variables)
+        .await
+        .unwrap_err();
+    match err {
+        QueryRunnerError::Plugin { code, message } => {
+            assert_eq!("invalid-input", code);
+            assert_eq!("customer_id must be positive, got -1", message);
+        }
+        err => return Err(anyhow!("unexpected error {err}")),
+    }
     Ok(())
 }
 
@@ -25,9 +71,50 @@ fn module_parameters() -> Result<()> {
     let m = st.get_plugin("test_collect")?;
     let params = st.get_metadata(m)?.parameters;
     assert_eq!(1, params.len());
-    let p = params.get(0).ok_or(anyhow!("no parameter at index 0"))?;
+    let p = params.first().ok_or(anyhow!("no parameter at index 0"))?;
     assert_eq!("customer_id", &p.name);
     assert_eq!(ParameterType::TypeInteger, p.parameter_type);
+    assert!(p.required);
+    assert_eq!(None, p.default_value);
+    assert_eq!("Customer whose orders to list", &p.description);
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_parameter_values() -> Result<()> {
+    let st = test_state()?;
+    orders_fixture(
+        st.get_connection("memory")?,
+        &[(1234, 123), (1235, 123), (1236, 45)],
+    )?;
+
+    let values = st
+        .parameter_values("test_collect", "memory", "customer_id")
+        .await?;
+    assert_eq!(
+        vec![
+            ParameterValue {
+                value: "45".to_string(),
+                label: "45".to_string(),
+            },
+            ParameterValue {
+                value: "123".to_string(),
+                label: "123".to_string(),
+            },
+        ],
+        values
+    );
+
+    assert!(matches!(
+        st.parameter_values("test_collect", "memory", "missing")
+            .await,
+        Err(QueryRunnerError::ParameterMissing(_))
+    ));
+    assert!(matches!(
+        st.parameter_values("test_collect2", "memory", "user_name")
+            .await,
+        Err(QueryRunnerError::Parameter(_))
+    ));
     Ok(())
 }
 
@@ -37,25 +124,53 @@ async fn sqlite_integer() -> Result<()> {
 }
 
 async fn integer_result(connection: &str) -> Result<()> {
-    let st = test_state()?;
+    integer_result_with(test_state()?, connection).await
+}
 
-    if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
-        let conn = conn.lock().unwrap();
-        conn.execute(
-            "CREATE TABLE Orders (
-                order_id     INTEGER PRIMARY KEY,
-                customer_id  INTEGER NOT NULL
-            )",
-            (),
-        )?;
-        conn.execute(
-            "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
-            (1234, 123, 1235, 123),
-        )?;
+#[tokio::test]
+async fn sqlite_compilers() -> Result<()> {
+    // Only the compilers built in are tested, `cargo test --features llvm,singlepass` covers them all.
+    for compiler in Compiler::ALL {
+        integer_result_with(State::load_from_disk_with(*compiler)?, "memory").await?;
     }
+    Ok(())
+}
 
-    let mut variables = HashMap::new();
-    variables.insert("customer_id", "123");
+#[tokio::test]
+async fn reload() -> Result<()> {
+    let mut st = test_state()?;
+    orders_fixture(st.get_connection("memory")?, &[(1234, 123)])?;
+
+    let reload = st.reload()?;
+    assert!(reload.plugins.is_empty());
+    assert!(reload.connections.is_empty());
+    assert!(reload.settings.is_empty());
+    // The in-memory database is the same, with its data.
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = reload
+        .state
+        .run_untyped("test_collect", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["order_id"],
+        &[&[ValueParam::DataInteger(Some(1234))]],
+    );
+
+    st.plugins.remove("test_collect");
+    st.connections.remove("memory");
+    let reload = st.reload()?;
+    assert_eq!(vec!["test_collect"], reload.plugins.added);
+    assert_eq!(vec!["memory"], reload.connections.added);
+    assert!(reload.state.get_plugin("test_collect").is_ok());
+    Ok(())
+}
+
+async fn integer_result_with(st: State, connection: &str) -> Result<()> {
+    orders_fixture(st.get_connection(connection)?, &[(1234, 123), (1235, 123)])?;
+
+    let variables = HashMap::from([("customer_id", "123")]);
     let res = st
         .run_untyped("test_collect", connection, &variables)
         .await?
@@ -72,6 +187,26 @@ async fn integer_result(connection: &str) -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn sqlite_columns() -> Result<()> {
+    let st = test_state()?;
+    orders_fixture(st.get_connection("memory")?, &[])?;
+
+    // Columns are described even without rows.
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = st
+        .run_untyped("test_collect", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(&res, &["order_id"], &[]);
+    let column = &res.columns[0];
+    assert!(matches!(column.column_type, ColumnType::TypeInteger));
+    assert_eq!(None, column.nullable);
+    assert_eq!(Some("INTEGER"), column.db_type.as_deref());
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn sqlite_text() -> Result<()> {
     result_text("memory").await
@@ -81,7 +216,7 @@ async fn result_text(connection: &str) -> Result<()> {
     let st = test_state()?;
 
     if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
-        let conn = conn.lock().unwrap();
+        let conn = conn.lock();
         conn.execute(
             "CREATE TABLE Orders (
                 order_id     TEXT PRIMARY KEY,
@@ -122,7 +257,7 @@ async fn result_bool(connection: &str) -> Result<()> {
     let st = test_state()?;
 
     if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
-        let conn = conn.lock().unwrap();
+        let conn = conn.lock();
         conn.execute(
             "CREATE TABLE Orders (
             order_id     BOOL,
@@ -162,7 +297,7 @@ async fn decimal_result(connection: &str) -> Result<()> {
     let st = test_state()?;
 
     if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
-        let conn = conn.lock().unwrap();
+        let conn = conn.lock();
         conn.execute(
             "CREATE TABLE Orders (
                 order_id     REAL,
@@ -194,6 +329,257 @@ async fn decimal_result(connection: &str) -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn sqlite_numeric() -> Result<()> {
+    numeric_result("memory").await
+}
+
+async fn numeric_result(connection: &str) -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (
+                order_id     DECIMAL(10,2),
+                customer_id  INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
+            ("123.40", 123, 124, 123),
+        )?;
+    }
+
+    let mut variables = HashMap::new();
+    variables.insert("customer_id", "123");
+    let res = st
+        .run_untyped("test_collect", connection, &variables)
+        .await?
+        .unwrap();
+    // SQLite stores numeric values as REAL or INTEGER.
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataNumeric(Some("123.4"))],
+            &[ValueParam::DataNumeric(Some("124"))],
+        ],
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_bytes() -> Result<()> {
+    bytes_result("memory").await
+}
+
+async fn bytes_result(connection: &str) -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (
+                order_id     BLOB,
+                customer_id  INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
+            (vec![0u8, 1, 2], 123, vec![255u8], 123),
+        )?;
+    }
+
+    let mut variables = HashMap::new();
+    variables.insert("customer_id", "123");
+    let res = st
+        .run_untyped("test_collect", connection, &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataBytes(Some(&[0, 1, 2]))],
+            &[ValueParam::DataBytes(Some(&[255]))],
+        ],
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_uuid() -> Result<()> {
+    uuid_result("memory").await
+}
+
+async fn uuid_result(connection: &str) -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (
+                order_id     UUID,
+                customer_id  INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
+            (
+                "67E55044-10B1-426F-9247-BB680E5FE0C8",
+                123,
+                vec![0u8; 16],
+                123,
+            ),
+        )?;
+    }
+
+    let mut variables = HashMap::new();
+    variables.insert("customer_id", "123");
+    let res = st
+        .run_untyped("test_collect", connection, &variables)
+        .await?
+        .unwrap();
+    // UUIDs come back in their canonical form, whether stored as text or bytes.
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataUuid(Some(
+                "67e55044-10b1-426f-9247-bb680e5fe0c8",
+            ))],
+            &[ValueParam::DataUuid(Some(
+                "00000000-0000-0000-0000-000000000000",
+            ))],
+        ],
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_varchar() -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (
+                order_id     VARCHAR(20),
+                customer_id  INT NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
+            ("1234", 123, "1235", 123),
+        )?;
+    }
+
+    let mut variables = HashMap::new();
+    variables.insert("customer_id", "123");
+    let res = st
+        .run_untyped("test_collect", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataString(Some("1234"))],
+            &[ValueParam::DataString(Some("1235"))],
+        ],
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_untyped() -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE RawOrders (
+                order_id     INTEGER,
+                customer_id  INTEGER NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "CREATE VIEW Orders AS SELECT order_id * 2 AS order_id, customer_id FROM RawOrders",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO RawOrders (order_id, customer_id) VALUES (?1, ?2), (?3, ?4)",
+            (1234, 123, 1235, 123),
+        )?;
+    }
+
+    let mut variables = HashMap::new();
+    variables.insert("customer_id", "123");
+    let res = st
+        .run_untyped("test_collect", "memory", &variables)
+        .await?
+        .unwrap();
+    // Expressions have no declared type, their values are read by storage class.
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataInteger(Some(2468))],
+            &[ValueParam::DataInteger(Some(2470))],
+        ],
+    );
+    assert!(matches!(
+        res.columns[0].column_type,
+        ColumnType::TypeUnknown
+    ));
+    assert_eq!(None, res.columns[0].db_type);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_mixed_column() -> Result<()> {
+    let st = test_state()?;
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        conn.lock().execute_batch(
+            "CREATE TABLE Orders (order_id INTEGER, customer_id INTEGER NOT NULL);
+            INSERT INTO Orders (order_id, customer_id) VALUES (1234, 123), ('pending', 123);",
+        )?;
+    }
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = st
+        .run_untyped("test_collect", "memory", &variables)
+        .await?
+        .unwrap();
+    // The text SQLite kept in the integer column is read as is, so the column has no single type.
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataInteger(Some(1234))],
+            &[ValueParam::DataString(Some("pending"))],
+        ],
+    );
+    assert!(matches!(
+        res.columns[0].column_type,
+        ColumnType::TypeUnknown
+    ));
+    assert_eq!(Some("INTEGER"), res.columns[0].db_type.as_deref());
+    Ok(())
+}
+
 #[tokio::test]
 async fn sqlite_null_result() -> Result<()> {
     null_result("memory").await
@@ -208,7 +594,7 @@ async fn null_result(connection: &str) -> Result<()> {
     let st = test_state()?;
 
     if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
-        let conn = conn.lock().unwrap();
+        let conn = conn.lock();
         conn.execute(
             "CREATE TABLE Users (
                 username  TEXT PRIMARY KEY,
@@ -261,14 +647,504 @@ async fn null_result(connection: &str) -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn postgres_arrays() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[(
+            "read_query",
+            "SELECT order_id, tags, quantities FROM OrderDetails
+            WHERE order_id = ANY({{ids}}) ORDER BY order_id",
+        )],
+    );
+
+    let ids = [
+        ScalarParam::ScalarInteger(1234),
+        ScalarParam::ScalarInteger(1235),
+    ];
+    let variables = [VariableParam {
+        name: "ids",
+        value: ValueParam::DataList(Some(&ids)),
+    }];
+    let res = st
+        .run_typed("test_configured", "postgres1", &variables)
+        .await?
+        .unwrap();
+    assert!(matches!(res.columns[1].column_type, ColumnType::TypeList));
+    assert_result(
+        &res,
+        &["order_id", "tags", "quantities"],
+        &[
+            &[
+                ValueParam::DataInteger(Some(1234)),
+                ValueParam::DataList(Some(&[
+                    ScalarParam::ScalarString("urgent"),
+                    ScalarParam::ScalarString("gift"),
+                ])),
+                ValueParam::DataList(Some(&[
+                    ScalarParam::ScalarInteger(1),
+                    ScalarParam::ScalarInteger(2),
+                ])),
+            ],
+            &[
+                ValueParam::DataInteger(Some(1235)),
+                ValueParam::DataList(Some(&[])),
+                ValueParam::DataList(None),
+            ],
+        ],
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn postgres_composite() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[(
+            "read_query",
+            "SELECT order_id, address FROM OrderDetails ORDER BY order_id",
+        )],
+    );
+
+    let res = st
+        .run_typed("test_configured", "postgres1", &[])
+        .await?
+        .unwrap();
+    assert!(matches!(res.columns[1].column_type, ColumnType::TypeRecord));
+    assert_result(
+        &res,
+        &["order_id", "address"],
+        &[
+            &[
+                ValueParam::DataInteger(Some(1234)),
+                ValueParam::DataRecord(Some(&[
+                    FieldParam {
+                        name: "street",
+                        value: ScalarParam::ScalarString("1 Main St"),
+                    },
+                    FieldParam {
+                        name: "city",
+                        value: ScalarParam::ScalarString("Springfield"),
+                    },
+                ])),
+            ],
+            &[
+                ValueParam::DataInteger(Some(1235)),
+                ValueParam::DataRecord(Some(&[
+                    FieldParam {
+                        name: "street",
+                        value: ScalarParam::ScalarString("2 High St"),
+                    },
+                    FieldParam {
+                        name: "city",
+                        value: ScalarParam::ScalarNull,
+                    },
+                ])),
+            ],
+            &[
+                ValueParam::DataInteger(Some(1236)),
+                ValueParam::DataRecord(None),
+            ],
+        ],
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn postgres_show() -> Result<()> {
+    // Statements Postgres cannot declare a cursor for still return their rows.
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[("read_query", "SHOW client_encoding")],
+    );
+
+    let res = st
+        .run_typed("test_configured", "postgres1", &[])
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["client_encoding"],
+        &[&[ValueParam::DataString(Some("UTF8"))]],
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn plugin_settings() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(&mut st, "test_collect2", &[("users_table", "Members")]);
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Members (
+                username  TEXT PRIMARY KEY,
+                name      TEXT NOT NULL,
+                email     TEXT NOT NULL
+            )",
+            (),
+        )?;
+        conn.execute(
+            "INSERT INTO Members (username, name, email) VALUES (?1, ?2, ?3)",
+            ("jane", "Jane Doe", "jane.doe@example.com"),
+        )?;
+    }
+
+    let variables = HashMap::from([("user_name", "jane")]);
+    let res = st
+        .run_untyped("test_collect2", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["name", "email"],
+        &[&[
+            ValueParam::DataString(Some("Jane Doe")),
+            ValueParam::DataString(Some("jane.doe@example.com")),
+        ]],
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_next_query() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[(
+            "next_query",
+            "SELECT order_id FROM Archived WHERE customer_id = {{customer_id}} ORDER BY order_id",
+        )],
+    );
+
+    orders_fixture(st.get_connection("memory")?, &[(1234, 123), (1236, 124)])?;
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        conn.lock().execute_batch(
+            "CREATE TABLE Archived (order_id INTEGER PRIMARY KEY, customer_id INTEGER NOT NULL);
+            INSERT INTO Archived (order_id, customer_id) VALUES (1001, 123), (1002, 124);",
+        )?;
+    }
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = st
+        .run_untyped("test_configured", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["order_id"],
+        &[
+            &[ValueParam::DataInteger(Some(1234))],
+            &[ValueParam::DataInteger(Some(1001))],
+        ],
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_batch_rows() -> Result<()> {
+    // `test_collect` exports `rows`, so it gets its rows in batches.
+    let mut st = test_state()?;
+    orders_fixture(
+        st.get_connection("memory")?,
+        &[(1, 123), (2, 123), (3, 123), (4, 123), (5, 123), (6, 45)],
+    )?;
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let expected: Vec<[ValueParam; 1]> = (1..=5)
+        .map(|id| [ValueParam::DataInteger(Some(id))])
+        .collect();
+    let expected: Vec<&[ValueParam]> = expected.iter().map(|row| row.as_slice()).collect();
+    // Full batches and a last partial one, or a single partial batch.
+    for batch_size in [2, 1_000] {
+        st.batch_size = batch_size;
+        let res = st
+            .run_untyped("test_collect", "memory", &variables)
+            .await?
+            .unwrap();
+        assert_result(&res, &["order_id"], &expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_sub_query() -> Result<()> {
+    sub_query("memory").await
+}
+
+#[tokio::test]
+async fn postgres_sub_query() -> Result<()> {
+    // Sub-queries share the only connection with the query of the run.
+    sub_query("postgres_single").await
+}
+
+async fn sub_query(connection: &str) -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[(
+            "lookup_query",
+            "SELECT note FROM OrderNotes WHERE order_id = {{order_id}}",
+        )],
+    );
+
+    orders_fixture(st.get_connection(connection)?, &[(1234, 123), (1235, 123)])?;
+    if let DBConnection::SqliteConnection(conn) = st.get_connection(connection)? {
+        conn.lock().execute_batch(
+            "CREATE TABLE OrderNotes (order_id INTEGER PRIMARY KEY, note TEXT NOT NULL);
+            INSERT INTO OrderNotes (order_id, note) VALUES (1234, 'urgent');",
+        )?;
+    }
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = st
+        .run_untyped("test_configured", connection, &variables)
+        .await?
+        .unwrap();
+    assert_result(
+        &res,
+        &["order_id", "lookup"],
+        &[
+            &[
+                ValueParam::DataInteger(Some(1234)),
+                ValueParam::DataString(Some("urgent")),
+            ],
+            &[
+                ValueParam::DataInteger(Some(1235)),
+                ValueParam::DataString(None),
+            ],
+        ],
+    );
+
+    // One query per row is one too many.
+    st.limits.max_sub_queries = Some(1);
+    let err = st
+        .run_untyped("test_configured", connection, &variables)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        err,
+        QueryRunnerError::Plugin { code, .. } if code == "too-many-queries"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_write() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_write",
+        &[(
+            "write_query",
+            "UPDATE Orders SET status = 'done' WHERE customer_id = {{customer_id}}",
+        )],
+    );
+
+    if let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? {
+        let conn = conn.lock();
+        conn.execute_batch(
+            "CREATE TABLE Orders (order_id INTEGER PRIMARY KEY, customer_id INTEGER NOT NULL, status TEXT);
+            INSERT INTO Orders (order_id, customer_id) VALUES (1234, 123), (1235, 123), (1236, 124);",
+        )?;
+    }
+
+    let variables = HashMap::from([("customer_id", "123")]);
+    let res = st
+        .run_untyped("test_write", "memory", &variables)
+        .await?
+        .unwrap();
+    assert_result(&res, &["affected"], &[&[ValueParam::DataInteger(Some(2))]]);
+    assert_eq!(2, done_orders(&st)?);
+
+    // A failing query rolls back the whole run.
+    with_settings(
+        &mut st,
+        "test_write",
+        &[
+            (
+                "write_query",
+                "UPDATE Orders SET status = 'done' WHERE customer_id = {{customer_id}} + 1",
+            ),
+            ("next_query", "UPDATE Missing SET status = 'done'"),
+        ],
+    );
+    let err = st
+        .run_untyped("test_write", "memory", &variables)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QueryRunnerError::Database(_)));
+    assert_eq!(2, done_orders(&st)?);
+
+    // Read-only connections refuse write plugins.
+    let err = st
+        .run_untyped("test_write", "memory_read_only", &variables)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, QueryRunnerError::ReadOnly));
+    Ok(())
+}
+
+/// Number of orders updated by the write plugin.
+fn done_orders(st: &State) -> Result<i64> {
+    let DBConnection::SqliteConnection(conn) = st.get_connection("memory")? else {
+        return Err(anyhow!("not a SQLite connection"));
+    };
+    let count = conn.lock().query_row(
+        "SELECT COUNT(*) FROM Orders WHERE status = 'done'",
+        (),
+        |r| r.get(0),
+    )?;
+    Ok(count)
+}
+
+#[tokio::test]
+async fn sqlite_stream() -> Result<()> {
+    // Send each row on its own, recording it first.
+    let mut st = test_state()?;
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[
+            ("emit_batches", "true"),
+            (
+                "lookup_query",
+                "INSERT INTO Sent (order_id) VALUES ({{order_id}}) RETURNING order_id",
+            ),
+        ],
+    );
+    st.batch_size = 1;
+    // A single connection, so that a run waits for the previous one to end.
+    let connection = st.get_connection("memory_single")?;
+
+    let orders: Vec<(i64, i64)> = (1..=10).map(|id| (id, 123)).collect();
+    orders_fixture(connection, &orders)?;
+    if let DBConnection::SqliteConnection(conn) = connection {
+        conn.lock()
+            .execute("CREATE TABLE Sent (order_id INTEGER PRIMARY KEY)", ())?;
+    }
+
+    let module = st.get_plugin("test_configured")?;
+    let variables = [VariableParam {
+        name: "customer_id",
+        value: ValueParam::DataInteger(Some(123)),
+    }];
+    let mut stream = st.run_stream(connection, module, &variables).await?;
+    let first = stream.try_next().await?.unwrap();
+    assert_result(
+        &first,
+        &["order_id", "lookup"],
+        &[&[
+            ValueParam::DataInteger(Some(1)),
+            ValueParam::DataInteger(Some(1)),
+        ]],
+    );
+    // Nobody reads the other rows: the run stops instead of going through them all.
+    drop(stream);
+
+    with_settings(
+        &mut st,
+        "test_configured",
+        &[("read_query", "SELECT COUNT(*) AS sent FROM Sent")],
+    );
+    let module = st.get_plugin("test_configured")?;
+    let connection = st.get_connection("memory_single")?;
+    let res = st.run(connection, module, &[]).await?.unwrap();
+    assert!(matches!(res.values[0][0], ValueResult::DataInteger(Some(sent)) if sent < 10));
+    Ok(())
+}
+
+#[tokio::test]
+async fn sqlite_memory_stream() -> Result<()> {
+    let mut st = test_state()?;
+    with_settings(&mut st, "test_configured", &[("emit_batches", "true")]);
+    st.batch_size = 1;
+    let connection = st.get_connection("memory")?;
+    orders_fixture(connection, &[(1234, 123), (1235, 123), (1236, 123)])?;
+
+    let module = st.get_plugin("test_configured")?;
+    let variables = [VariableParam {
+        name: "customer_id",
+        value: ValueParam::DataInteger(Some(123)),
+    }];
+    let mut stream = st.run_stream(connection, module, &variables).await?;
+    let first = stream.try_next().await?.unwrap();
+    assert_result(
+        &first,
+        &["order_id"],
+        &[&[ValueParam::DataInteger(Some(1234))]],
+    );
+    // The database can be used while the other batches of the run wait to be read.
+    if let DBConnection::SqliteConnection(conn) = connection {
+        let count: i64 = conn
+            .lock()
+            .query_row("SELECT COUNT(*) FROM Orders", (), |r| r.get(0))?;
+        assert_eq!(3, count);
+    }
+    let rest: Vec<QueryResult> = stream.try_collect().await?;
+    assert_eq!(3, rest.len());
+    assert_result(
+        &rest[0],
+        &["order_id"],
+        &[&[ValueParam::DataInteger(Some(1235))]],
+    );
+    assert_result(
+        &rest[1],
+        &["order_id"],
+        &[&[ValueParam::DataInteger(Some(1236))]],
+    );
+    // Nothing left for the end.
+    assert_result(&rest[2], &["order_id"], &[]);
+    Ok(())
+}
+
 fn test_state() -> Result<State> {
-    State::load_from_disk()
+    Ok(State::load_from_disk()?)
+}
+
+/// Replace the settings of a plugin.
+fn with_settings(st: &mut State, plugin: &str, settings: &[(&str, &str)]) {
+    st.settings.insert(
+        plugin.to_string(),
+        settings
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+    );
+}
+
+/// Create the `Orders` table most test plugins query on a SQLite connection, with the given
+/// `(order_id, customer_id)` rows. Postgres test databases already have their own.
+fn orders_fixture(connection: &DBConnection, rows: &[(i64, i64)]) -> Result<()> {
+    if let DBConnection::SqliteConnection(conn) = connection {
+        let conn = conn.lock();
+        conn.execute(
+            "CREATE TABLE Orders (order_id INTEGER PRIMARY KEY, customer_id INTEGER NOT NULL)",
+            (),
+        )?;
+        for row in rows {
+            conn.execute(
+                "INSERT INTO Orders (order_id, customer_id) VALUES (?1, ?2)",
+                *row,
+            )?;
+        }
+    }
+    Ok(())
 }
 
 fn assert_result(res: &QueryResult, names: &[&str], values: &[&[ValueParam]]) {
-    assert_eq!(names.len(), res.names.len());
-    for (expected, got) in names.iter().zip(res.names.iter()) {
-        assert_eq!(expected, got)
+    assert_eq!(names.len(), res.columns.len());
+    for (expected, got) in names.iter().zip(res.columns.iter()) {
+        assert_eq!(expected, &got.name)
     }
     assert_eq!(values.len(), res.values.len());
     for (expected, got) in values.iter().zip(res.values.iter()) {
@@ -288,9 +1164,81 @@ fn assert_result(res: &QueryResult, names: &[&str], values: &[&[ValueParam]]) {
                     matches!(got_value, ValueResult::DataString(s2) if s1 == &s2.as_deref())
                 ),
                 ValueParam::DataTimestamp(t1) => assert!(
-                    matches!(got_value, ValueResult::DataTimestamp(t2) if t1 == &t2.as_deref())
+                    matches!(got_value, ValueResult::DataTimestamp(t2) if timestamp_eq(t1, t2))
+                ),
+                ValueParam::DataDate(d1) => {
+                    assert!(matches!(got_value, ValueResult::DataDate(d2) if d1 == d2))
+                }
+                ValueParam::DataTime(t1) => {
+                    assert!(matches!(got_value, ValueResult::DataTime(t2) if t1 == t2))
+                }
+                ValueParam::DataNumeric(n1) => assert!(
+                    matches!(got_value, ValueResult::DataNumeric(n2) if n1 == &n2.as_deref())
                 ),
+                ValueParam::DataBytes(b1) => {
+                    assert!(matches!(got_value, ValueResult::DataBytes(b2) if b1 == &b2.as_deref()))
+                }
+                ValueParam::DataUuid(u1) => {
+                    assert!(matches!(got_value, ValueResult::DataUuid(u2) if u1 == &u2.as_deref()))
+                }
+                ValueParam::DataJson(j1) => {
+                    assert!(matches!(got_value, ValueResult::DataJson(j2) if j1 == &j2.as_deref()))
+                }
+                ValueParam::DataList(l1) => {
+                    assert!(matches!(got_value, ValueResult::DataList(l2) if list_eq(l1, l2)))
+                }
+                ValueParam::DataRecord(r1) => {
+                    assert!(matches!(got_value, ValueResult::DataRecord(r2) if record_eq(r1, r2)))
+                }
             }
         }
     }
 }
+
+fn timestamp_eq(t1: &Option<Timestamp>, t2: &Option<Timestamp>) -> bool {
+    t1.map(|t| (t.micros, t.offset_seconds)) == t2.map(|t| (t.micros, t.offset_seconds))
+}
+
+fn list_eq(l1: &Option<&[ScalarParam]>, l2: &Option<Vec<ScalarResult>>) -> bool {
+    match (l1, l2) {
+        (Some(l1), Some(l2)) => {
+            l1.len() == l2.len() && l1.iter().zip(l2.iter()).all(|(s1, s2)| scalar_eq(s1, s2))
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn record_eq(r1: &Option<&[FieldParam]>, r2: &Option<Vec<FieldResult>>) -> bool {
+    match (r1, r2) {
+        (Some(r1), Some(r2)) => {
+            r1.len() == r2.len()
+                && r1
+                    .iter()
+                    .zip(r2.iter())
+                    .all(|(f1, f2)| f1.name == f2.name && scalar_eq(&f1.value, &f2.value))
+        }
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+fn scalar_eq(s1: &ScalarParam, s2: &ScalarResult) -> bool {
+    match (s1, s2) {
+        (ScalarParam::ScalarNull, ScalarResult::ScalarNull) => true,
+        (ScalarParam::ScalarString(s1), ScalarResult::ScalarString(s2)) => s1 == s2,
+        (ScalarParam::ScalarTimestamp(t1), ScalarResult::ScalarTimestamp(t2)) => {
+            timestamp_eq(&Some(*t1), &Some(*t2))
+        }
+        (ScalarParam::ScalarBoolean(b1), ScalarResult::ScalarBoolean(b2)) => b1 == b2,
+        (ScalarParam::ScalarInteger(i1), ScalarResult::ScalarInteger(i2)) => i1 == i2,
+        (ScalarParam::ScalarDecimal(d1), ScalarResult::ScalarDecimal(d2)) => d1 == d2,
+        (ScalarParam::ScalarDate(d1), ScalarResult::ScalarDate(d2)) => d1 == d2,
+        (ScalarParam::ScalarTime(t1), ScalarResult::ScalarTime(t2)) => t1 == t2,
+        (ScalarParam::ScalarNumeric(n1), ScalarResult::ScalarNumeric(n2)) => n1 == n2,
+        (ScalarParam::ScalarBytes(b1), ScalarResult::ScalarBytes(b2)) => b1 == b2,
+        (ScalarParam::ScalarUuid(u1), ScalarResult::ScalarUuid(u2)) => u1 == u2,
+        (ScalarParam::ScalarJson(j1), ScalarResult::ScalarJson(j2)) => j1 == j2,
+        _ => false,
+    }
+}
@@ -2,7 +2,7 @@ use std::cell::RefCell;
 
 use wai_bindgen_rust::Handle;
 
-wai_bindgen_rust::export!("query.wai");
+wai_bindgen_rust::export!("../query-runner/query.wai");
 
 use crate::query::*;
 
@@ -14,18 +14,33 @@ pub struct Query {}
 impl crate::query::Query for Query {
     fn metadata() -> QueryMetadata {
         metadata!("test plugin collecting results",
-        "customer_id" => Integer)
+        "customer_id" => Integer {
+            description: "Customer whose orders to list",
+            values_query: "SELECT DISTINCT customer_id FROM Orders ORDER BY customer_id",
+        })
     }
 
-    fn start(variables: Vec<Variable>) -> Handle<Execution> {
-        Execution {
+    fn start(variables: Vec<Variable>) -> Result<Handle<Execution>, PluginError> {
+        if let Some(Value::DataInteger(Some(id))) = variables.first().map(|v| &v.value) {
+            if *id < 0 {
+                return Err(plugin_error!(
+                    "invalid-input",
+                    "customer_id must be positive, got {id}"
+                ));
+            }
+        }
+        log(
+            LogLevel::Debug,
+            &format!("starting with {} variables", variables.len()),
+        );
+        Ok(Execution {
             query_string: String::from(
                 "SELECT order_id FROM Orders WHERE customer_id = {{customer_id}} ORDER BY order_id",
             ),
             variables,
             data: RefCell::from(Vec::new()),
         }
-        .into()
+        .into())
     }
 }
 
@@ -44,17 +59,44 @@ impl crate::query::Execution for Execution {
         self.variables.clone()
     }
 
-    fn row(&self, data: Vec<Variable>) -> Option<QueryResult> {
-        self.data
-            .borrow_mut()
-            .push(data.into_iter().map(|v| v.value).collect());
-        None
+    fn row(&self, data: Vec<Variable>) -> Result<Option<QueryResult>, PluginError> {
+        self.collect(data);
+        Ok(None)
+    }
+
+    fn columns(&self, _columns: Vec<Column>) -> Result<(), PluginError> {
+        // `end` gets the columns too.
+        Ok(())
+    }
+
+    fn rows(&self, batch: Vec<Vec<Value>>) -> Result<Option<QueryResult>, PluginError> {
+        self.data.borrow_mut().extend(batch);
+        Ok(None)
+    }
+
+    fn affected(&self, _count: u64) -> Result<Option<QueryResult>, PluginError> {
+        // Only reads.
+        Ok(None)
     }
 
-    fn end(&self, columns: Vec<String>) -> Option<QueryResult> {
-        Some(QueryResult {
-            names: columns,
+    fn end(&self, columns: Vec<Column>) -> Result<Option<QueryResult>, PluginError> {
+        Ok(Some(QueryResult {
+            columns,
             values: self.data.borrow_mut().drain(..).collect(),
-        })
+        }))
+    }
+
+    fn next(&self) -> Result<Option<NextQuery>, PluginError> {
+        // A single query.
+        Ok(None)
+    }
+}
+
+impl Execution {
+    /// Keep the values of a row.
+    fn collect(&self, data: Vec<Variable>) {
+        self.data
+            .borrow_mut()
+            .push(data.into_iter().map(|v| v.value).collect());
     }
 }
@@ -2,30 +2,31 @@ use std::cell::RefCell;
 
 use wai_bindgen_rust::Handle;
 
-wai_bindgen_rust::export!("query.wai");
+wai_bindgen_rust::export!("../query-runner/query-basic.wai");
 
-use crate::query::*;
+use crate::query_basic::*;
 
 use paste::paste;
 use query_common::*;
 
-pub struct Query {}
+pub struct QueryBasic {}
 
-impl crate::query::Query for Query {
+impl crate::query_basic::QueryBasic for QueryBasic {
     fn metadata() -> QueryMetadata {
         metadata!("test plugin collecting results",
-        "user_name" => String)
+        "user_name" => String { description: "Login of the user" })
     }
 
-    fn start(variables: Vec<Variable>) -> Handle<Execution> {
-        Execution {
-            query_string: String::from(
-                "SELECT name, email FROM Users WHERE username = {{user_name}}",
+    fn start(variables: Vec<Variable>) -> Result<Handle<Execution>, PluginError> {
+        let table = config_get("users_table").unwrap_or_else(|| String::from("Users"));
+        Ok(Execution {
+            query_string: format!(
+                "SELECT name, email FROM {table} WHERE username = {{{{user_name}}}}"
             ),
             variables,
             data: RefCell::from(Vec::new()),
         }
-        .into()
+        .into())
     }
 }
 
@@ -35,7 +36,7 @@ pub struct Execution {
     data: RefCell<Vec<Vec<Value>>>,
 }
 
-impl crate::query::Execution for Execution {
+impl crate::query_basic::Execution for Execution {
     fn query_string(&self) -> String {
         self.query_string.clone()
     }
@@ -44,17 +45,17 @@ impl crate::query::Execution for Execution {
         self.variables.clone()
     }
 
-    fn row(&self, data: Vec<Variable>) -> Option<QueryResult> {
+    fn row(&self, data: Vec<Variable>) -> Result<Option<QueryResult>, PluginError> {
         self.data
             .borrow_mut()
             .push(data.into_iter().map(|v| v.value).collect());
-        None
+        Ok(None)
     }
 
-    fn end(&self, columns: Vec<String>) -> Option<QueryResult> {
-        Some(QueryResult {
-            names: columns,
+    fn end(&self, columns: Vec<Column>) -> Result<Option<QueryResult>, PluginError> {
+        Ok(Some(QueryResult {
+            columns,
             values: self.data.borrow_mut().drain(..).collect(),
-        })
+        }))
     }
 }
@@ -0,0 +1,180 @@
+use std::cell::RefCell;
+
+use wai_bindgen_rust::Handle;
+
+wai_bindgen_rust::export!("../query-runner/query.wai");
+
+use crate::query::*;
+
+use paste::paste;
+use query_common::*;
+
+/// Settings:
+/// - `read_query`: the query to run instead of listing the orders of the customer.
+/// - `next_query`: a query collecting more rows after the first one.
+/// - `lookup_query`: a query run for each row to add a `lookup` column.
+/// - `emit_batches`: send the rows of each batch as they come instead of all at the end.
+pub struct Query {}
+
+impl crate::query::Query for Query {
+    fn metadata() -> QueryMetadata {
+        metadata!("test plugin running the queries of its settings",
+        "customer_id" => Integer { description: "Customer whose orders to list" })
+    }
+
+    fn start(variables: Vec<Variable>) -> Result<Handle<Execution>, PluginError> {
+        Ok(Execution {
+            query_string: config_get("read_query").unwrap_or_else(|| {
+                String::from(
+                    "SELECT order_id FROM Orders WHERE customer_id = {{customer_id}} ORDER BY order_id",
+                )
+            }),
+            variables,
+            data: RefCell::from(Vec::new()),
+            columns: RefCell::from(Vec::new()),
+            next_query: RefCell::from(config_get("next_query")),
+            lookup_query: config_get("lookup_query"),
+            emit_batches: config_get("emit_batches").is_some(),
+        }
+        .into())
+    }
+}
+
+pub struct Execution {
+    query_string: String,
+    variables: Vec<Variable>,
+    data: RefCell<Vec<Vec<Value>>>,
+    /// Columns of the query, as rows come in batches.
+    columns: RefCell<Vec<Column>>,
+    /// Query collecting more rows after the first one, if configured.
+    next_query: RefCell<Option<String>>,
+    /// Query run for each row to add a `lookup` column, if configured.
+    lookup_query: Option<String>,
+    /// Send the rows of each batch as they come instead of all at the end, if configured.
+    emit_batches: bool,
+}
+
+impl crate::query::Execution for Execution {
+    fn query_string(&self) -> String {
+        self.query_string.clone()
+    }
+
+    fn variables(&self) -> Vec<Variable> {
+        self.variables.clone()
+    }
+
+    fn row(&self, data: Vec<Variable>) -> Result<Option<QueryResult>, PluginError> {
+        self.collect(data)?;
+        Ok(None)
+    }
+
+    fn columns(&self, columns: Vec<Column>) -> Result<(), PluginError> {
+        *self.columns.borrow_mut() = columns;
+        Ok(())
+    }
+
+    fn rows(&self, batch: Vec<Vec<Value>>) -> Result<Option<QueryResult>, PluginError> {
+        let columns = self.columns.borrow();
+        for values in batch {
+            let data = columns
+                .iter()
+                .zip(values)
+                .map(|(column, value)| Variable {
+                    name: column.name.clone(),
+                    value,
+                })
+                .collect();
+            self.collect(data)?;
+        }
+        if !self.emit_batches {
+            return Ok(None);
+        }
+        Ok(Some(QueryResult {
+            columns: self.result_columns(columns.clone()),
+            values: self.data.borrow_mut().drain(..).collect(),
+        }))
+    }
+
+    fn affected(&self, _count: u64) -> Result<Option<QueryResult>, PluginError> {
+        // Only reads.
+        Ok(None)
+    }
+
+    fn end(&self, columns: Vec<Column>) -> Result<Option<QueryResult>, PluginError> {
+        // Wait for the rows of the next query.
+        if self.next_query.borrow().is_some() {
+            return Ok(None);
+        }
+        Ok(Some(QueryResult {
+            columns: self.result_columns(columns),
+            values: self.data.borrow_mut().drain(..).collect(),
+        }))
+    }
+
+    fn next(&self) -> Result<Option<NextQuery>, PluginError> {
+        Ok(self
+            .next_query
+            .borrow_mut()
+            .take()
+            .map(|query_string| NextQuery {
+                query_string,
+                variables: self.variables.clone(),
+            }))
+    }
+}
+
+impl Execution {
+    /// The columns of the query, with the `lookup` one if configured.
+    fn result_columns(&self, mut columns: Vec<Column>) -> Vec<Column> {
+        if self.lookup_query.is_some() {
+            columns.push(Column {
+                name: "lookup".to_string(),
+                column_type: ColumnType::TypeUnknown,
+                nullable: Some(true),
+                db_type: None,
+            });
+        }
+        columns
+    }
+
+    /// Keep the values of a row, with the result of the lookup query if configured.
+    fn collect(&self, data: Vec<Variable>) -> Result<(), PluginError> {
+        let lookup = match &self.lookup_query {
+            Some(lookup_query) => Some(lookup(lookup_query, &data)?),
+            None => None,
+        };
+        let mut values: Vec<Value> = data.into_iter().map(|v| v.value).collect();
+        values.extend(lookup);
+        self.data.borrow_mut().push(values);
+        Ok(())
+    }
+}
+
+/// Run the lookup query with the row values, returning the first value it finds.
+fn lookup(lookup_query: &str, data: &[Variable]) -> Result<Value, PluginError> {
+    let variables: Vec<host::Variable> = data
+        .iter()
+        .map(|v| host::Variable {
+            name: &v.name,
+            value: match &v.value {
+                Value::DataInteger(i) => host::ValueParam::DataInteger(*i),
+                Value::DataString(s) => host::ValueParam::DataString(s.as_deref()),
+                _ => host::ValueParam::DataString(None),
+            },
+        })
+        .collect();
+    let result = query(lookup_query, &variables).map_err(|err| PluginError {
+        code: err.code,
+        message: err.message,
+    })?;
+    let value = result
+        .values
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_iter().next());
+    Ok(match value {
+        Some(host::ValueResult::DataInteger(i)) => Value::DataInteger(i),
+        Some(host::ValueResult::DataString(s)) => Value::DataString(s),
+        _ => Value::DataString(None),
+    })
+}
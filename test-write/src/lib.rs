@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+
+use wai_bindgen_rust::Handle;
+
+wai_bindgen_rust::export!("../query-runner/query.wai");
+
+use crate::query::*;
+
+use paste::paste;
+use query_common::*;
+
+/// Settings:
+/// - `write_query`: the statement to run instead of marking the orders of the customer as done.
+/// - `next_query`: a statement run after the first one, in the same transaction.
+pub struct Query {}
+
+impl crate::query::Query for Query {
+    fn metadata() -> QueryMetadata {
+        QueryMetadata {
+            mode: QueryMode::Write,
+            ..metadata!("test plugin running the statements of its settings",
+            "customer_id" => Integer { description: "Customer whose orders to update" })
+        }
+    }
+
+    fn start(variables: Vec<Variable>) -> Result<Handle<Execution>, PluginError> {
+        Ok(Execution {
+            query_string: config_get("write_query").unwrap_or_else(|| {
+                String::from("UPDATE Orders SET status = 'done' WHERE customer_id = {{customer_id}}")
+            }),
+            variables,
+            affected: RefCell::from(Vec::new()),
+            next_query: RefCell::from(config_get("next_query")),
+        }
+        .into())
+    }
+}
+
+pub struct Execution {
+    query_string: String,
+    variables: Vec<Variable>,
+    /// Number of rows each statement changed.
+    affected: RefCell<Vec<Vec<Value>>>,
+    /// Statement run after the first one, if configured.
+    next_query: RefCell<Option<String>>,
+}
+
+impl crate::query::Execution for Execution {
+    fn query_string(&self) -> String {
+        self.query_string.clone()
+    }
+
+    fn variables(&self) -> Vec<Variable> {
+        self.variables.clone()
+    }
+
+    fn row(&self, _data: Vec<Variable>) -> Result<Option<QueryResult>, PluginError> {
+        // Statements return no rows.
+        Ok(None)
+    }
+
+    fn columns(&self, _columns: Vec<Column>) -> Result<(), PluginError> {
+        Ok(())
+    }
+
+    fn rows(&self, _batch: Vec<Vec<Value>>) -> Result<Option<QueryResult>, PluginError> {
+        Ok(None)
+    }
+
+    fn affected(&self, count: u64) -> Result<Option<QueryResult>, PluginError> {
+        self.affected
+            .borrow_mut()
+            .push(vec![Value::DataInteger(Some(count as i64))]);
+        Ok(None)
+    }
+
+    fn end(&self, _columns: Vec<Column>) -> Result<Option<QueryResult>, PluginError> {
+        // Wait for the next statement.
+        if self.next_query.borrow().is_some() {
+            return Ok(None);
+        }
+        Ok(Some(QueryResult {
+            columns: vec![Column {
+                name: "affected".to_string(),
+                column_type: ColumnType::TypeInteger,
+                nullable: Some(false),
+                db_type: None,
+            }],
+            values: self.affected.borrow_mut().drain(..).collect(),
+        }))
+    }
+
+    fn next(&self) -> Result<Option<NextQuery>, PluginError> {
+        Ok(self
+            .next_query
+            .borrow_mut()
+            .take()
+            .map(|query_string| NextQuery {
+                query_string,
+                variables: self.variables.clone(),
+            }))
+    }
+}